@@ -0,0 +1,101 @@
+// Compression ratio against history window size on a high-cardinality stream.
+//
+//     cargo bench --bench window
+//
+// Records are drawn from a pool of a few thousand distinct lines, built from
+// corpus/logs.txt with a random per-line token, so a record usually repeats a
+// line last seen thousands of records ago and only a window that still holds
+// it can match the token.
+
+extern crate dssc;
+
+use dssc::chunked::ChunkedCompressor;
+use dssc::chunkmap::ChunkMap;
+use dssc::Compressor;
+use std::time::Instant;
+
+const POOL: usize = 8000;
+const RECORDS: usize = 40_000;
+
+fn xorshift(seed: &mut u64) -> u64 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+    *seed
+}
+
+fn records() -> Vec<Vec<u8>> {
+    let corpus = include_bytes!("../corpus/logs.txt");
+    let lines: Vec<&[u8]> = corpus.split_inclusive(|&b| b == b'\n').collect();
+    let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+    let pool: Vec<Vec<u8>> = (0..POOL)
+        .map(|i| {
+            let token = (0..3).map(|_| format!("{:016x}", xorshift(&mut seed))).collect::<String>();
+            let mut record = format!("token={} ", token).into_bytes();
+            record.extend_from_slice(lines[i % lines.len()]);
+            record
+        })
+        .collect();
+    (0..RECORDS)
+        .map(|_| pool[(xorshift(&mut seed) % POOL as u64) as usize].clone())
+        .collect()
+}
+
+fn run(name: &str, window: usize, records: &[Vec<u8>], mut encoder: Box<dyn Compressor>, mut decoder: Box<dyn Compressor>) {
+    let original: usize = records.iter().map(|r| r.len()).sum();
+    let mut compressed = 0;
+    let mut frames = Vec::with_capacity(records.len());
+    let start = Instant::now();
+    for record in records {
+        let mut buf = Vec::new();
+        compressed += encoder.encode(record, &mut buf).unwrap();
+        frames.push(buf);
+    }
+    let encode = start.elapsed();
+    let start = Instant::now();
+    for (frame, record) in frames.iter().zip(records) {
+        let mut out = Vec::new();
+        decoder.decode(frame, &mut out).unwrap();
+        assert_eq!(&out, record);
+    }
+    let decode = start.elapsed();
+    let mb = original as f64 / (1 << 20) as f64;
+    println!(
+        "{:<9}{:>8}{:>9.3}{:>12.1}{:>12.1}",
+        name,
+        window,
+        compressed as f64 / original as f64,
+        mb / encode.as_secs_f64(),
+        mb / decode.as_secs_f64()
+    );
+}
+
+fn main() {
+    let records = records();
+    println!("{:<9}{:>8}{:>9}{:>12}{:>12}", "", "window", "ratio", "enc MB/s", "dec MB/s");
+    for &window in [64, 256, 1024, 4096, 16384, 65536].iter() {
+        // insert every record straight into main, so the window is all that decides
+        // whether a line is still there
+        let chunkmap = || {
+            Box::new(
+                ChunkMap::builder()
+                    .insert_threshold(0.0)
+                    .eden_size(0)
+                    .main_size(window)
+                    .build(),
+            )
+        };
+        run("chunkmap", window, &records, chunkmap(), chunkmap());
+    }
+    for &window in [64, 256, 1024, 4096, 16384, 65536].iter() {
+        let chunked = || {
+            Box::new(
+                ChunkedCompressor::builder()
+                    .insert_threshold(0.0)
+                    .cache_size(window)
+                    .build(),
+            )
+        };
+        run("chunked", window, &records, chunked(), chunked());
+    }
+}
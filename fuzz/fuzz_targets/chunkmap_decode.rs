@@ -0,0 +1,35 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate dssc;
+
+use dssc::chunkmap::ChunkMap;
+use dssc::Compressor;
+
+const SAMPLES: [&[u8]; 3] = [
+    b"Jan 12 10:00:01 host sshd[100]: Accepted publickey for root\n",
+    b"Jan 12 10:00:02 host sshd[101]: Accepted publickey for admin\n",
+    b"Jan 12 10:00:03 host cron[7]: (root) CMD (run-parts /etc/cron.hourly)\n",
+];
+
+fuzz_target!(|data: &[u8]| {
+    let mut encoder = ChunkMap::new(0.5);
+    let mut decoder = ChunkMap::new(0.5);
+    let mut buf = Vec::new();
+    for sample in SAMPLES.iter() {
+        buf.clear();
+        encoder.encode(sample, &mut buf).unwrap();
+        decoder.decode(&buf, &mut Vec::new()).unwrap();
+    }
+
+    if decoder.decode(data, &mut Vec::new()).is_ok() {
+        return;
+    }
+
+    // a rejected frame must leave the decoder in lockstep with the encoder
+    buf.clear();
+    encoder.encode(SAMPLES[1], &mut buf).unwrap();
+    let mut out = Vec::new();
+    decoder.decode(&buf, &mut out).unwrap();
+    assert_eq!(&out[..], SAMPLES[1]);
+});
@@ -1,51 +1,625 @@
-use std::cmp::Ordering;
+extern crate byteorder;
+extern crate fnv;
 
-const CACHE_SIZE: usize = 256;
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use self::fnv::{FnvHashMap, FnvHasher};
+use super::state::{read_slot, write_slot};
+use super::varint::{next_uvarint, put_uvarint, read_uvarint, write_uvarint};
+use super::DsscError;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+
+pub const DEFAULT_CACHE_SIZE: usize = 256;
+
+/// Nominal bytes of bookkeeping per cached line, per chunk of it in a match
+/// index, and per distinct key of a match index. They are fixed rather than
+/// measured so that both ends of a stream evict the same lines for a memory
+/// budget, whatever platform they run on.
+pub const ENTRY_OVERHEAD: usize = 48;
+pub const MATCH_OVERHEAD: usize = 8;
+pub const KEY_OVERHEAD: usize = 40;
+
+/// What a line of `len` bytes with `indexed` chunks in a match index counts
+/// against a memory budget.
+pub fn entry_cost(len: usize, indexed: usize) -> usize {
+    ENTRY_OVERHEAD + len + indexed * MATCH_OVERHEAD
+}
 
-#[derive(Eq)]
 pub struct CacheEntry {
     pub hits: usize,
     pub data: Vec<u8>,
 }
 
-impl Ord for CacheEntry {
-    fn cmp(&self, other: &CacheEntry) -> Ordering {
-        self.hits.cmp(&other.hits).reverse()
+/// Lines stay in the slot they were inserted into until they are evicted,
+/// frames refer to them by slot. Evictions for a memory budget leave holes.
+pub type VecCache = Vec<Option<CacheEntry>>;
+
+pub const FP_INSERT: u8 = 1;
+pub const FP_REMOVE: u8 = 2;
+pub const FP_HIT: u8 = 3;
+pub const FP_PROMOTE: u8 = 4;
+pub const FP_RESET: u8 = 5;
+pub const FP_PIN: u8 = 6;
+
+/// Folds one cache mutation into a running fingerprint. Encoder and decoder
+/// call this for the same mutations in the same order, so their fingerprints
+/// only stay equal while their caches do.
+pub fn fingerprint_mix(fingerprint: u64, op: u8, slot: usize, data: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::with_key(fingerprint);
+    hasher.write_u8(op);
+    hasher.write(&(slot as u64).to_le_bytes());
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// Identifies a line by its content, for policies that remember evicted lines.
+pub fn line_key(data: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// Decides on the encoding side which records go into the history cache. The
+/// decision travels in the frame, see `flag_insert`, so a decoder follows it
+/// whatever policy it was built with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InsertPolicy {
+    /// Records whose frame is longer than this fraction of the record.
+    Threshold(f32),
+    /// Every Nth record, starting with the first.
+    Every(u64),
+}
+
+impl InsertPolicy {
+    /// Whether record number `index`, `len` bytes that encoded to `clen`,
+    /// goes into the cache.
+    pub fn decide(self, index: u64, clen: usize, len: usize) -> bool {
+        match self {
+            InsertPolicy::Threshold(threshold) => clen as f32 / len as f32 > threshold,
+            InsertPolicy::Every(n) => index.is_multiple_of(n),
+        }
+    }
+
+    pub fn save_state(self, w: &mut dyn Write) -> Result<(), DsscError> {
+        match self {
+            InsertPolicy::Threshold(threshold) => {
+                w.write_u8(1)?;
+                w.write_f32::<LittleEndian>(threshold)?;
+            }
+            InsertPolicy::Every(n) => {
+                w.write_u8(2)?;
+                write_uvarint(w, n)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_state(r: &mut dyn Read) -> Result<InsertPolicy, DsscError> {
+        match r.read_u8()? {
+            1 => Ok(InsertPolicy::Threshold(r.read_f32::<LittleEndian>()?)),
+            2 => match read_uvarint(r)? {
+                0 => Err(DsscError::CorruptState("inserting every 0th record")),
+                n => Ok(InsertPolicy::Every(n)),
+            },
+            _ => Err(DsscError::CorruptState("unknown insert policy")),
+        }
+    }
+}
+
+/// Folds `insert` into the low bit of the varint tag starting the frame at
+/// `buf[start..]`, and returns whether the record goes into the cache. An
+/// empty frame has no tag to carry the bit, its record is never inserted.
+pub fn flag_insert(buf: &mut Vec<u8>, start: usize, insert: bool) -> bool {
+    if buf.len() == start {
+        return false;
+    }
+    let mut end = start;
+    let tag = next_uvarint(buf, &mut end).expect("frame does not start with a tag");
+    let mut varint_buf = [0; 10];
+    let varint_len = put_uvarint(&mut varint_buf, tag << 1 | insert as u64);
+    buf.splice(start..end, varint_buf[..varint_len].iter().cloned());
+    insert
+}
+
+/// Splits the tag starting a frame into the block tag and the insert bit.
+pub fn split_insert(tag: u64) -> (u64, bool) {
+    (tag >> 1, tag & 1 != 0)
+}
+
+/// Picks which line a full history cache gives up. Encoder and decoder feed
+/// their policies the same calls in the same order, so every policy has to be
+/// deterministic; the chosen policy is recorded in the stream header.
+pub trait CachePolicy: Send {
+    /// The line with content `key` now lives in `slot`.
+    fn on_insert(&mut self, slot: usize, key: u64);
+    /// A record referenced `len` bytes of the line in `slot`.
+    fn on_hit(&mut self, slot: usize, len: usize);
+    /// `slot` was emptied, evicted or not.
+    fn on_remove(&mut self, slot: usize);
+    /// The slot to evict to make room for the line with content `key`. Only
+    /// asked while the policy tracks at least one slot.
+    fn victim(&mut self, key: u64) -> usize;
+
+    /// Number of slots the policy tracks.
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn contains(&self, slot: usize) -> bool;
+
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError>;
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    Lru,
+    #[default]
+    LfuAging,
+    Arc,
+    Fifo,
+}
+
+impl Policy {
+    pub fn id(self) -> u8 {
+        match self {
+            Policy::Lru => 1,
+            Policy::LfuAging => 2,
+            Policy::Arc => 3,
+            Policy::Fifo => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Policy> {
+        match id {
+            1 => Some(Policy::Lru),
+            2 => Some(Policy::LfuAging),
+            3 => Some(Policy::Arc),
+            4 => Some(Policy::Fifo),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::Lru => "lru",
+            Policy::LfuAging => "lfu",
+            Policy::Arc => "arc",
+            Policy::Fifo => "fifo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Policy> {
+        match name {
+            "lru" => Some(Policy::Lru),
+            "lfu" => Some(Policy::LfuAging),
+            "arc" => Some(Policy::Arc),
+            "fifo" => Some(Policy::Fifo),
+            _ => None,
+        }
+    }
+
+    /// A fresh policy for a cache of `capacity` lines.
+    pub fn build(self, capacity: usize) -> Box<dyn CachePolicy> {
+        match self {
+            Policy::Lru => Box::new(LruPolicy::default()),
+            Policy::LfuAging => Box::new(LfuAgingPolicy::new(capacity)),
+            Policy::Arc => Box::new(ArcPolicy::new(capacity)),
+            Policy::Fifo => Box::new(FifoPolicy::default()),
+        }
+    }
+}
+
+fn write_slots<'a, I: ExactSizeIterator<Item = &'a usize>>(
+    w: &mut dyn Write,
+    slots: I,
+) -> Result<(), DsscError> {
+    write_uvarint(w, slots.len() as u64)?;
+    for &slot in slots {
+        write_slot(w, slot)?;
+    }
+    Ok(())
+}
+
+fn read_slots(r: &mut dyn Read) -> Result<Queue<usize>, DsscError> {
+    let mut slots = Queue::default();
+    for _ in 0..read_uvarint(r)? {
+        let slot = read_slot(r)?;
+        if slots.contains(slot) {
+            return Err(DsscError::CorruptState("slot listed twice"));
+        }
+        slots.push_back(slot);
+    }
+    Ok(slots)
+}
+
+/// Items in the order they were queued, oldest first, that can also be taken
+/// out from the middle in logarithmic time. An item queued more than once is
+/// taken out oldest copy first.
+struct Queue<T> {
+    order: BTreeMap<u64, T>,
+    // sequence numbers of each item's copies, oldest first
+    seqs: FnvHashMap<T, VecDeque<u64>>,
+    next: u64,
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Queue {
+            order: BTreeMap::new(),
+            seqs: FnvHashMap::default(),
+            next: 0,
+        }
+    }
+}
+
+impl<T: Copy + Eq + Hash> Queue<T> {
+    fn push_back(&mut self, item: T) {
+        self.order.insert(self.next, item);
+        self.seqs.entry(item).or_default().push_back(self.next);
+        self.next += 1;
+    }
+    fn remove(&mut self, item: T) -> bool {
+        let seq = match self.seqs.get_mut(&item) {
+            Some(seqs) => seqs.pop_front().unwrap(),
+            None => return false,
+        };
+        if self.seqs[&item].is_empty() {
+            self.seqs.remove(&item);
+        }
+        self.order.remove(&seq);
+        true
+    }
+    fn front(&self) -> Option<T> {
+        self.order.values().next().cloned()
+    }
+    fn pop_front(&mut self) -> Option<T> {
+        let item = self.front()?;
+        self.remove(item);
+        Some(item)
+    }
+    fn contains(&self, item: T) -> bool {
+        self.seqs.contains_key(&item)
+    }
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+    fn iter(&self) -> impl ExactSizeIterator<Item = &T> {
+        self.order.values()
     }
 }
 
-impl PartialOrd for CacheEntry {
-    fn partial_cmp(&self, other: &CacheEntry) -> Option<Ordering> {
-        Some(self.cmp(other).reverse())
+/// Evicts the line inserted first, hits don't matter.
+#[derive(Default)]
+pub struct FifoPolicy {
+    order: Queue<usize>,
+}
+
+impl CachePolicy for FifoPolicy {
+    fn on_insert(&mut self, slot: usize, _key: u64) {
+        self.order.push_back(slot);
+    }
+    fn on_hit(&mut self, _slot: usize, _len: usize) {}
+    fn on_remove(&mut self, slot: usize) {
+        self.order.remove(slot);
+    }
+    fn victim(&mut self, _key: u64) -> usize {
+        self.order.front().unwrap()
+    }
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+    fn contains(&self, slot: usize) -> bool {
+        self.order.contains(slot)
+    }
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        write_slots(w, self.order.iter())
     }
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError> {
+        self.order = read_slots(r)?;
+        Ok(())
+    }
+}
+
+/// Evicts the line that went longest without a hit.
+#[derive(Default)]
+pub struct LruPolicy {
+    // least recently used first
+    order: Queue<usize>,
 }
 
-impl PartialEq for CacheEntry {
-    fn eq(&self, other: &CacheEntry) -> bool {
-        self.hits == other.hits
+impl CachePolicy for LruPolicy {
+    fn on_insert(&mut self, slot: usize, _key: u64) {
+        self.order.push_back(slot);
+    }
+    fn on_hit(&mut self, slot: usize, _len: usize) {
+        if self.order.remove(slot) {
+            self.order.push_back(slot);
+        }
+    }
+    fn on_remove(&mut self, slot: usize) {
+        self.order.remove(slot);
+    }
+    fn victim(&mut self, _key: u64) -> usize {
+        self.order.front().unwrap()
+    }
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+    fn contains(&self, slot: usize) -> bool {
+        self.order.contains(slot)
+    }
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        write_slots(w, self.order.iter())
+    }
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError> {
+        self.order = read_slots(r)?;
+        Ok(())
     }
 }
 
-pub type VecCache = Vec<CacheEntry>;
+/// Evicts the line with the fewest hit bytes, the oldest of those on a tie.
+/// Every `capacity` inserts all counts are halved, so lines that were popular
+/// long ago eventually make way.
+pub struct LfuAgingPolicy {
+    capacity: usize,
+    inserts: u64,
+    // slot -> (hit bytes, insert sequence number)
+    counts: BTreeMap<usize, (u64, u64)>,
+    // the same, least hit first
+    ranked: BTreeSet<((u64, u64), usize)>,
+}
 
-pub trait DSSCache {
-    fn cache_insert(&mut self, buf: &[u8]);
+impl LfuAgingPolicy {
+    pub fn new(capacity: usize) -> Self {
+        LfuAgingPolicy {
+            capacity: capacity.max(1),
+            inserts: 0,
+            counts: BTreeMap::new(),
+            ranked: BTreeSet::new(),
+        }
+    }
 }
 
-impl DSSCache for VecCache {
-    fn cache_insert(&mut self, buf: &[u8]) {
-        self.sort_unstable();
-        let len = self.len();
-        if len == CACHE_SIZE {
-            self[len - 1] = CacheEntry {
-                hits: 0,
-                data: buf.to_vec(),
+impl CachePolicy for LfuAgingPolicy {
+    fn on_insert(&mut self, slot: usize, _key: u64) {
+        self.counts.insert(slot, (0, self.inserts));
+        self.ranked.insert(((0, self.inserts), slot));
+        self.inserts += 1;
+        if self.inserts.is_multiple_of(self.capacity as u64) {
+            for count in self.counts.values_mut() {
+                count.0 /= 2;
+            }
+            self.ranked = self.counts.iter().map(|(&slot, &count)| (count, slot)).collect();
+        }
+    }
+    fn on_hit(&mut self, slot: usize, len: usize) {
+        if let Some(count) = self.counts.get_mut(&slot) {
+            self.ranked.remove(&(*count, slot));
+            count.0 += len as u64;
+            self.ranked.insert((*count, slot));
+        }
+    }
+    fn on_remove(&mut self, slot: usize) {
+        if let Some(count) = self.counts.remove(&slot) {
+            self.ranked.remove(&(count, slot));
+        }
+    }
+    fn victim(&mut self, _key: u64) -> usize {
+        self.ranked.iter().next().unwrap().1
+    }
+    fn len(&self) -> usize {
+        self.counts.len()
+    }
+    fn contains(&self, slot: usize) -> bool {
+        self.counts.contains_key(&slot)
+    }
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        write_uvarint(w, self.inserts)?;
+        write_uvarint(w, self.counts.len() as u64)?;
+        for (&slot, &(hits, seq)) in &self.counts {
+            write_slot(w, slot)?;
+            write_uvarint(w, hits)?;
+            write_uvarint(w, seq)?;
+        }
+        Ok(())
+    }
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError> {
+        self.inserts = read_uvarint(r)?;
+        self.counts.clear();
+        for _ in 0..read_uvarint(r)? {
+            let slot = read_slot(r)?;
+            let count = (read_uvarint(r)?, read_uvarint(r)?);
+            if self.counts.insert(slot, count).is_some() {
+                return Err(DsscError::CorruptState("slot listed twice"));
             }
+        }
+        self.ranked = self.counts.iter().map(|(&slot, &count)| (count, slot)).collect();
+        Ok(())
+    }
+}
+
+/// Adaptive replacement cache (Megiddo and Modha). Lines seen once and lines
+/// hit since their insert are kept in separate LRU lists, and the content keys
+/// of recently evicted lines decide how much room each list gets. Lines that
+/// come back while their key is still remembered are treated as hit. The
+/// split only adapts when an insert has to evict.
+pub struct ArcPolicy {
+    capacity: usize,
+    // target length of `recent`
+    target: usize,
+    // least recently used first
+    recent: Queue<usize>,
+    frequent: Queue<usize>,
+    // content keys of lines evicted from each list, oldest first
+    recent_ghosts: Queue<u64>,
+    frequent_ghosts: Queue<u64>,
+    keys: BTreeMap<usize, u64>,
+}
+
+impl ArcPolicy {
+    pub fn new(capacity: usize) -> Self {
+        ArcPolicy {
+            capacity,
+            target: 0,
+            recent: Queue::default(),
+            frequent: Queue::default(),
+            recent_ghosts: Queue::default(),
+            frequent_ghosts: Queue::default(),
+            keys: BTreeMap::new(),
+        }
+    }
+}
+
+impl CachePolicy for ArcPolicy {
+    fn on_insert(&mut self, slot: usize, key: u64) {
+        self.keys.insert(slot, key);
+        if self.recent_ghosts.remove(key) || self.frequent_ghosts.remove(key) {
+            self.frequent.push_back(slot);
         } else {
-            self.push(CacheEntry {
-                hits: 0,
-                data: buf.to_vec(),
-            })
+            self.recent.push_back(slot);
         }
+        while self.recent.len() + self.recent_ghosts.len() > self.capacity
+            && self.recent_ghosts.pop_front().is_some()
+        {}
+        while self.recent.len() + self.frequent.len() + self.recent_ghosts.len()
+            + self.frequent_ghosts.len() > 2 * self.capacity
+            && self.frequent_ghosts.pop_front().is_some()
+        {}
+    }
+    fn on_hit(&mut self, slot: usize, _len: usize) {
+        if self.recent.remove(slot) || self.frequent.remove(slot) {
+            self.frequent.push_back(slot);
+        }
+    }
+    fn on_remove(&mut self, slot: usize) {
+        let key = match self.keys.remove(&slot) {
+            Some(key) => key,
+            None => return,
+        };
+        if self.recent.remove(slot) {
+            self.recent_ghosts.push_back(key);
+        } else if self.frequent.remove(slot) {
+            self.frequent_ghosts.push_back(key);
+        }
+    }
+    fn victim(&mut self, key: u64) -> usize {
+        let (recent, frequent) = (self.recent_ghosts.len(), self.frequent_ghosts.len());
+        let in_frequent_ghosts = self.frequent_ghosts.contains(key);
+        if self.recent_ghosts.contains(key) {
+            self.target = (self.target + (frequent / recent).max(1)).min(self.capacity);
+        } else if in_frequent_ghosts {
+            self.target = self.target.saturating_sub((recent / frequent).max(1));
+        }
+        let len = self.recent.len();
+        if self.frequent.is_empty()
+            || len != 0 && (len > self.target || in_frequent_ghosts && len == self.target)
+        {
+            self.recent.front().unwrap()
+        } else {
+            self.frequent.front().unwrap()
+        }
+    }
+    fn len(&self) -> usize {
+        self.keys.len()
     }
+    fn contains(&self, slot: usize) -> bool {
+        self.keys.contains_key(&slot)
+    }
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        write_uvarint(w, self.target as u64)?;
+        for list in [&self.recent, &self.frequent].iter() {
+            write_uvarint(w, list.len() as u64)?;
+            for slot in list.iter() {
+                write_slot(w, *slot)?;
+                write_uvarint(w, self.keys[slot])?;
+            }
+        }
+        for ghosts in [&self.recent_ghosts, &self.frequent_ghosts].iter() {
+            write_uvarint(w, ghosts.len() as u64)?;
+            for &key in ghosts.iter() {
+                write_uvarint(w, key)?;
+            }
+        }
+        Ok(())
+    }
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError> {
+        let mut arc = ArcPolicy::new(self.capacity);
+        arc.target = (read_uvarint(r)? as usize).min(self.capacity);
+        for frequent in [false, true].iter() {
+            for _ in 0..read_uvarint(r)? {
+                let slot = read_slot(r)?;
+                if arc.keys.insert(slot, read_uvarint(r)?).is_some() {
+                    return Err(DsscError::CorruptState("slot listed twice"));
+                }
+                if *frequent {
+                    arc.frequent.push_back(slot);
+                } else {
+                    arc.recent.push_back(slot);
+                }
+            }
+        }
+        for frequent in [false, true].iter() {
+            for _ in 0..read_uvarint(r)? {
+                let key = read_uvarint(r)?;
+                if *frequent {
+                    arc.frequent_ghosts.push_back(key);
+                } else {
+                    arc.recent_ghosts.push_back(key);
+                }
+            }
+        }
+        if arc.recent_ghosts.len() + arc.frequent_ghosts.len() > 2 * self.capacity {
+            return Err(DsscError::CorruptState("too many evicted keys"));
+        }
+        *self = arc;
+        Ok(())
+    }
+}
+
+#[test]
+pub fn policy_test() {
+    let victims = |policy: Policy| {
+        let mut p = policy.build(3);
+        for slot in 0..3 {
+            p.on_insert(slot, slot as u64);
+        }
+        p.on_hit(0, 10);
+        p.on_hit(1, 1);
+        let first = p.victim(3);
+        p.on_remove(first);
+        p.on_insert(first, 3);
+
+        let mut state = Vec::new();
+        p.save_state(&mut state).unwrap();
+        let mut restored = policy.build(3);
+        restored.load_state(&mut &state[..]).unwrap();
+        assert_eq!(restored.len(), 3);
+        assert!(restored.contains(first));
+        (first, restored.victim(4))
+    };
+    assert_eq!(victims(Policy::Fifo), (0, 1));
+    assert_eq!(victims(Policy::Lru), (2, 0));
+    assert_eq!(victims(Policy::LfuAging), (2, 2));
+    assert_eq!(victims(Policy::Arc), (2, 2));
+
+    // a line that comes back soon after its eviction counts as frequent, and
+    // grows the room for lines seen once
+    let mut arc = ArcPolicy::new(2);
+    arc.on_insert(0, 10);
+    arc.on_insert(1, 11);
+    arc.on_hit(1, 5);
+    assert_eq!(arc.victim(12), 0);
+    arc.on_remove(0);
+    arc.on_insert(0, 12);
+    assert_eq!(arc.victim(10), 1);
+    assert_eq!(arc.target, 1);
+    arc.on_remove(1);
+    arc.on_insert(1, 10);
+    assert!(arc.frequent.iter().eq(&[1]));
+    assert!(arc.frequent_ghosts.iter().eq(&[11]));
 }
@@ -1,110 +1,385 @@
-use super::varint::{put_uvarint, uvarint};
-use super::Compressor;
-use super::cache::{DSSCache, VecCache};
+extern crate byteorder;
+extern crate fnv;
+
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use self::fnv::FnvHashMap;
+use super::chunkmap::{chunk_key, ChunkKey};
+use super::varint::{next_uvarint, put_uvarint, read_uvarint, uvarint_len, write_uvarint};
+use super::{Compressor, DsscError};
+use super::cache::{entry_cost, fingerprint_mix, flag_insert, line_key, split_insert, CacheEntry,
+                   CachePolicy, InsertPolicy, Policy, VecCache, FP_HIT, FP_INSERT, FP_REMOVE,
+                   KEY_OVERHEAD};
+use super::dict::Dictionary;
+use super::state::{read_bytes, read_preamble, write_bytes, write_preamble, KIND_CHUNKED};
+use std::collections::BTreeSet;
 use std::fmt;
+use std::io::{Read, Write};
+
+pub use super::cache::DEFAULT_CACHE_SIZE;
+pub const CHUNK_SIZE: usize = 4;
+/// Chunks held by more haystacks than this say little about which one is
+/// closest and would make every lookup as slow as a scan of the cache.
+const MAX_CANDIDATES: usize = 256;
+/// Lines a record is matched against, those sharing the most chunks with it.
+const MAX_LINES: usize = 4;
 
-const CHUNK_SIZE: usize = 4;
+/// Slots holding each chunk, with the offset it first appears at in the slot.
+type Index = FnvHashMap<ChunkKey, FnvHashMap<usize, usize>>;
 
+/// Encodes each record as references into earlier records kept in a cache.
+///
+/// A frame is a sequence of blocks with nothing before or between them, an
+/// empty record encodes to an empty frame. Each block starts with a varint
+/// tag, shifted left by one in the first block of a frame to make room for a
+/// low bit saying whether the record goes into the cache:
+///
+/// * `0`, then a varint length and that many literal bytes.
+/// * `1`, then a varint offset and a varint length, copying from the cache
+///   line the previous copy in this frame named. It is an error as the
+///   first copy of a frame.
+/// * `n >= 2`, then a varint offset and a varint length, copying from the
+///   cache line in slot `n - 2`.
+///
+/// A copy must lie within its line. Once a frame is decoded each line it
+/// copies from gets one hit, in the order the lines are first named, then the
+/// record goes into the cache if the frame says so. It is inserted into the
+/// first empty slot, a new slot while there are fewer than `cache_size`, or
+/// the slot the policy evicts. With a memory budget, lines are evicted first
+/// until the record fits, and a record costing more than the whole budget is
+/// not inserted. Encoder and decoder apply the same updates, so their caches
+/// stay in step.
 pub struct ChunkedCompressor {
     cache: VecCache,
-    insert_threshold: f32,
+    // empty slots below `cache.len()`
+    vacant: BTreeSet<usize>,
+    index: Index,
+    policy_kind: Policy,
+    policy: Box<dyn CachePolicy>,
+    cache_size: usize,
+    memory_budget: Option<usize>,
+    memory_usage: usize,
+    insert_policy: InsertPolicy,
+    // records encoded so far, for `InsertPolicy::Every`
+    encoded: u64,
+    fingerprint: u64,
 }
 
-fn compress(needle: &[u8], out_buf: &mut Vec<u8>, cache: &VecCache) -> usize {
-    use std::str::from_utf8;
-    eprintln!{"{:?}", from_utf8(needle)};
-    if cache.len() == 0 {
-        out_buf.push(0);
-        Block {
-            block_type: BlockType::Original,
-            offset: 0,
-            needle_off: 0,
-            len: needle.len(),
-        }.encode(needle, out_buf);
-        return 0;
+pub struct ChunkedCompressorBuilder {
+    insert_policy: InsertPolicy,
+    policy: Policy,
+    cache_size: usize,
+    memory_budget: Option<usize>,
+}
+
+impl ChunkedCompressorBuilder {
+    /// Records that compress worse than this ratio are added to the cache.
+    pub fn insert_threshold(mut self, insert_threshold: f32) -> Self {
+        self.insert_policy = InsertPolicy::Threshold(insert_threshold);
+        self
     }
-    let matches = chunk_match(needle, &cache);
-    //eprintln!("matches {:?}", matches);
-    let max: usize = matches
-        .iter()
-        .map(|m| m.iter().filter(|&&o| o != 0).count())
-        .max()
-        .expect("haystacks are empty");
-    let mut max_block = (0, 0, None);
-    for (hi, result) in matches.iter().enumerate() {
-        if result.iter().filter(|&&o| o != 0).count() != max {
-            continue;
-        }
-        let blocks = expand_blocks(needle, &cache[hi].data, result);
-        let score = blocks
-            .iter()
-            .filter(|b| b.block_type == BlockType::Delta)
-            .map(|b| b.len)
-            .sum();
-        if max_block.0 <= score {
-            max_block = (score, hi, Some(blocks))
+
+    /// Decides which records are added to the cache. Only the encoder uses
+    /// it, frames tell the decoder what to insert.
+    pub fn insert_policy(mut self, insert_policy: InsertPolicy) -> Self {
+        assert!(insert_policy != InsertPolicy::Every(0), "cannot insert every 0th record");
+        self.insert_policy = insert_policy;
+        self
+    }
+
+    /// Decides which line makes room once the cache is full.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Lines kept in the cache. Blocks refer to them by a varint index, so
+    /// windows past 126 lines cost an extra byte whenever a block switches
+    /// lines.
+    pub fn cache_size(mut self, cache_size: usize) -> Self {
+        assert!(cache_size != 0, "cache cannot be empty");
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// Also evicts lines while the cache would take more than `bytes`, on top
+    /// of the line limit.
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    pub fn build(self) -> ChunkedCompressor {
+        ChunkedCompressor {
+            cache: Vec::new(),
+            vacant: BTreeSet::new(),
+            index: Index::default(),
+            policy_kind: self.policy,
+            policy: self.policy.build(self.cache_size),
+            cache_size: self.cache_size,
+            memory_budget: self.memory_budget,
+            memory_usage: 0,
+            insert_policy: self.insert_policy,
+            encoded: 0,
+            fingerprint: 0,
         }
     }
-    eprintln!("{:?}", max_block.2);
-    out_buf.push(max_block.1 as u8);
-    for block in max_block.2.expect("No candidate was found") {
-        block.encode(needle, out_buf);
+}
+
+/// Encodes `needle` against the lines sharing the most chunks with it and
+/// returns the lines it references, in the order they are first referenced.
+fn compress(needle: &[u8], out_buf: &mut Vec<u8>, cache: &VecCache, index: &Index) -> Vec<usize> {
+    //eprintln!("{:?}", ::std::str::from_utf8(needle));
+    let matches = chunk_match(needle, cache, index);
+    //eprintln!("matches {:?}", matches);
+    let mut deltas = Vec::new();
+    for (hi, result) in matches {
+        let haystack = &cache[hi].as_ref().expect("matched an empty slot").data;
+        let blocks = expand_blocks(needle, haystack, hi, &result);
+        deltas.extend(blocks.into_iter().filter(|b| b.block_type == BlockType::Delta));
+    }
+    let mut lines = Vec::new();
+    let mut prev_line = None;
+    for block in stitch(needle, &deltas) {
+        //eprintln!("{}", block);
+        block.encode(needle, prev_line, out_buf);
+        if block.block_type == BlockType::Delta {
+            if !lines.contains(&block.line) {
+                lines.push(block.line);
+            }
+            prev_line = Some(block.line);
+        }
     }
     //eprintln!("{:?} needle", needle);
     //eprintln!("{:?} output", out_buf);
-    max_block.1
+    lines
 }
 
-fn decompress(buf: &[u8], out_buf: &mut Vec<u8>, haystacks: &VecCache) -> usize {
-    let hi = buf[0] as usize;
-    let mut bi = 1;
-    if haystacks.len() == 0 {
-        bi += 1;
-        let (len, len_len) = uvarint(&buf[bi..]);
-        if len_len <= 0 {
-            panic!("Something is wrong with length varint");
+/// Picks the blocks to encode `needle` with from the `Delta` blocks found
+/// against each line. At every position the block reaching furthest wins,
+/// ties going to the line of the block before it, and bytes no block covers
+/// for less than it costs are left as literals.
+fn stitch(needle: &[u8], deltas: &[Block]) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut prev_line = None;
+    let mut literal = 0;
+    let mut pos = 0;
+    while pos < needle.len() {
+        let mut best: Option<Block> = None;
+        for delta in deltas {
+            if delta.needle_off > pos || delta.needle_off + delta.len <= pos {
+                continue;
+            }
+            let skip = pos - delta.needle_off;
+            let block = Block {
+                block_type: BlockType::Delta,
+                line: delta.line,
+                needle_off: pos,
+                offset: delta.offset + skip,
+                len: delta.len - skip,
+            };
+            if block.cost(prev_line) >= block.len {
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some(ref best) => {
+                    block.len > best.len
+                        || block.len == best.len
+                            && Some(block.line) == prev_line
+                            && Some(best.line) != prev_line
+                }
+            };
+            if better {
+                best = Some(block);
+            }
         }
-        bi += len_len as usize;
-        out_buf.extend_from_slice(&buf[bi..bi + len as usize]);
-        return 0;
+        match best {
+            Some(block) => {
+                if literal < pos {
+                    blocks.push(Block {
+                        block_type: BlockType::Original,
+                        line: 0,
+                        needle_off: literal,
+                        offset: 0,
+                        len: pos - literal,
+                    });
+                }
+                pos += block.len;
+                literal = pos;
+                prev_line = Some(block.line);
+                blocks.push(block);
+            }
+            // nothing pays off here, so nothing will until the next block starts
+            None => {
+                pos = deltas
+                    .iter()
+                    .map(|delta| delta.needle_off)
+                    .filter(|&off| off > pos)
+                    .min()
+                    .unwrap_or(needle.len());
+            }
+        }
+    }
+    if literal < needle.len() {
+        blocks.push(Block {
+            block_type: BlockType::Original,
+            line: 0,
+            needle_off: literal,
+            offset: 0,
+            len: needle.len() - literal,
+        });
+    }
+    blocks
+}
+
+/// Offset of the first occurrence of every distinct chunk in `data`.
+fn first_chunks(data: &[u8]) -> FnvHashMap<ChunkKey, usize> {
+    let mut first = FnvHashMap::default();
+    for (offset, chunk) in data.windows(CHUNK_SIZE).enumerate() {
+        first.entry(chunk_key(chunk)).or_insert(offset);
     }
+    first
+}
+
+/// Decodes the blocks in `buf` and returns the lines they reference, in the
+/// order they are first referenced, and whether the record goes into the cache.
+fn decompress(
+    buf: &[u8],
+    out_buf: &mut Vec<u8>,
+    haystacks: &VecCache,
+) -> Result<(Vec<usize>, bool), DsscError> {
+    let mut lines = Vec::new();
+    let mut prev_line = None;
+    let mut insert = false;
+    let mut bi = 0;
     while bi < buf.len() {
-        if buf[bi] == 0 {
+        let tag_off = bi;
+        let mut tag = next_uvarint(buf, &mut bi)?;
+        if tag_off == 0 {
+            let (first, flag) = split_insert(tag);
+            tag = first;
+            insert = flag;
+        }
+        if tag == 0 {
             //original
-            bi += 1;
-            let (len, len_len) = uvarint(&buf[bi..]);
-            if len_len <= 0 {
-                panic!("Something is wrong with length varint");
+            let len = next_uvarint(buf, &mut bi)? as usize;
+            if buf.len() - bi < len {
+                return Err(DsscError::TruncatedBlock { offset: bi, len });
             }
-            bi += len_len as usize;
-            out_buf.extend_from_slice(&buf[bi..bi + len as usize]);
-            bi += len as usize;
+            out_buf.extend_from_slice(&buf[bi..bi + len]);
+            bi += len;
+            continue;
+        }
+        let line = if tag == 1 {
+            prev_line.ok_or(DsscError::MissingLine { offset: tag_off })?
         } else {
-            let (mut offset, offset_len) = uvarint(&buf[bi..]);
-            if offset_len <= 0 {
-                panic!("Something is wrong with offset varint");
-            }
-            offset -= 1;
-            bi += offset_len as usize;
-            let (len, len_len) = uvarint(&buf[bi..]);
-            if len_len <= 0 {
-                panic!("Something is wrong with length varint");
-            }
-            bi += len_len as usize;
-            out_buf.extend_from_slice(
-                &haystacks[hi].data[offset as usize..offset as usize + len as usize],
-            );
+            (tag - 2) as usize
+        };
+        let haystack = &haystacks
+            .get(line)
+            .and_then(Option::as_ref)
+            .ok_or(DsscError::UnknownSlot { slot: line })?
+            .data;
+        let offset = next_uvarint(buf, &mut bi)? as usize;
+        let len = next_uvarint(buf, &mut bi)? as usize;
+        if offset > haystack.len() || haystack.len() - offset < len {
+            return Err(DsscError::OffsetOutOfRange { line, offset, len });
         }
+        out_buf.extend_from_slice(&haystack[offset..offset + len]);
+        if !lines.contains(&line) {
+            lines.push(line);
+        }
+        prev_line = Some(line);
     }
-    hi
+    Ok((lines, insert))
 }
 
 impl ChunkedCompressor {
     pub fn new(insert_threshold: f32) -> Self {
-        ChunkedCompressor {
-            cache: Vec::new(),
-            insert_threshold: insert_threshold,
+        ChunkedCompressor::builder().insert_threshold(insert_threshold).build()
+    }
+
+    pub fn builder() -> ChunkedCompressorBuilder {
+        ChunkedCompressorBuilder {
+            insert_policy: InsertPolicy::Threshold(0.5),
+            policy: Policy::default(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            memory_budget: None,
+        }
+    }
+
+    fn hit(&mut self, hit_index: usize) {
+        if let Some(Some(entry)) = self.cache.get_mut(hit_index) {
+            entry.hits += 1;
+            self.policy.on_hit(hit_index, 1);
+            self.fingerprint = fingerprint_mix(self.fingerprint, FP_HIT, hit_index, &[]);
+        }
+    }
+
+    fn insert(&mut self, buf: &[u8]) {
+        let key = line_key(buf);
+        let chunks = first_chunks(buf);
+        let cost = entry_cost(buf.len(), chunks.len());
+        if let Some(budget) = self.memory_budget {
+            if cost + chunks.len() * KEY_OVERHEAD > budget {
+                return;
+            }
+            while self.memory_usage + cost + self.new_keys(&chunks) * KEY_OVERHEAD > budget {
+                let victim = self.policy.victim(key);
+                self.evict(victim);
+            }
         }
+        let slot = match self.vacant.iter().next().cloned() {
+            Some(slot) => slot,
+            None if self.cache.len() < self.cache_size => {
+                self.cache.push(None);
+                self.cache.len() - 1
+            }
+            None => {
+                let victim = self.policy.victim(key);
+                self.evict(victim);
+                victim
+            }
+        };
+        self.vacant.remove(&slot);
+        let mut keys = 0;
+        for (chunk, offset) in chunks {
+            let slots = self.index.entry(chunk).or_insert_with(|| {
+                keys += 1;
+                FnvHashMap::default()
+            });
+            slots.insert(slot, offset);
+        }
+        self.memory_usage += keys * KEY_OVERHEAD;
+        self.cache[slot] = Some(CacheEntry {
+            hits: 0,
+            data: buf.to_vec(),
+        });
+        self.memory_usage += cost;
+        self.policy.on_insert(slot, key);
+        self.fingerprint = fingerprint_mix(self.fingerprint, FP_INSERT, slot, buf);
+    }
+
+    fn evict(&mut self, slot: usize) {
+        let entry = self.cache[slot].take().expect("policy picked an empty slot");
+        self.vacant.insert(slot);
+        let chunks = first_chunks(&entry.data);
+        self.memory_usage -= entry_cost(entry.data.len(), chunks.len());
+        for chunk in chunks.keys() {
+            let slots = self.index.get_mut(chunk).expect("chunk was not indexed");
+            slots.remove(&slot);
+            if slots.is_empty() {
+                self.index.remove(chunk);
+                self.memory_usage -= KEY_OVERHEAD;
+            }
+        }
+        self.policy.on_remove(slot);
+        self.fingerprint = fingerprint_mix(self.fingerprint, FP_REMOVE, slot, &[]);
+    }
+
+    /// How many of `chunks` the index does not hold yet.
+    fn new_keys(&self, chunks: &FnvHashMap<ChunkKey, usize>) -> usize {
+        chunks.keys().filter(|chunk| !self.index.contains_key(chunk)).count()
     }
 }
 
@@ -115,73 +390,210 @@ impl Default for ChunkedCompressor {
 }
 
 impl Compressor for ChunkedCompressor {
-    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) {
+    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
         //let mut out_buf = Vec::new();
         let old_buf_len = out_buf.len();
-        let hit_index = compress(in_buf, out_buf, &self.cache);
+        let lines = compress(in_buf, out_buf, &self.cache, &self.index);
+        let insert = self
+            .insert_policy
+            .decide(self.encoded, out_buf.len() - old_buf_len, in_buf.len());
+        self.encoded += 1;
+        let insert = flag_insert(out_buf, old_buf_len, insert);
         let clen = out_buf.len() - old_buf_len;
 
-        if self.cache.len() != 0 {
-            self.cache[hit_index].hits += 1;
+        for line in lines {
+            self.hit(line);
         }
 
-        let cr = clen as f32 / in_buf.len() as f32;
-        eprintln!(
-            "cr {}/{}={} cache entry {}",
-            clen,
-            in_buf.len(),
-            cr,
-            hit_index,
-        );
-        if cr > self.insert_threshold {
-            self.cache.cache_insert(&in_buf);
+        //eprintln!("cr {}/{}", clen, in_buf.len());
+        if insert {
+            self.insert(in_buf);
         }
+        Ok(clen)
     }
-    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) {
+    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
         let old_buf_len = out_buf.len();
-        let hit_index = decompress(in_buf, out_buf, &self.cache);
+        let (lines, insert) = match decompress(in_buf, out_buf, &self.cache) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                // blocks before the bad one were already written out
+                out_buf.truncate(old_buf_len);
+                return Err(err);
+            }
+        };
         let dlen = out_buf.len() - old_buf_len;
 
-        if self.cache.len() != 0 {
-            self.cache[hit_index].hits += 1;
+        for line in lines {
+            self.hit(line);
         }
-        let cr = in_buf.len() as f32 / dlen as f32;
-        if cr > self.insert_threshold {
-            self.cache.cache_insert(&out_buf);
+        if insert {
+            self.insert(&out_buf[old_buf_len..]);
         }
+        Ok(dlen)
     }
-}
 
-// for each haystack returns a list of indexes where each chunk of needle was found, 0 means not found
-fn chunk_match(needle: &[u8], haystacks: &VecCache) -> Vec<Vec<usize>> {
-    let mut results = Vec::new();
-    for haystack in haystacks {
-        let mut chunks = Vec::new();
-        'next_chunk: for chunk in needle.chunks(CHUNK_SIZE) {
-            // check the chunk following the last chunk first
-            if let Some(&last) = chunks.last() {
-                if last != 0 {
-                    let hi = last - 1 + CHUNK_SIZE;
-                    if hi + CHUNK_SIZE < haystack.data.len() - 1 &&
-                        (&haystack.data[hi..hi + CHUNK_SIZE] == chunk)
-                    {
-                        chunks.push(hi + 1);
-                        continue 'next_chunk;
-                    }
+    fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Cached lines plus one `ENTRY_OVERHEAD` per line, one `MATCH_OVERHEAD`
+    /// per distinct chunk of a line in the index and one `KEY_OVERHEAD` per
+    /// distinct chunk in the index.
+    fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    fn prime(&mut self, dict: &Dictionary) -> Result<(), DsscError> {
+        for record in &dict.records {
+            self.insert(record);
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        write_preamble(w, KIND_CHUNKED)?;
+        self.insert_policy.save_state(w)?;
+        write_uvarint(w, self.encoded)?;
+        w.write_u8(self.policy_kind.id())?;
+        write_uvarint(w, self.cache_size as u64)?;
+        write_uvarint(w, self.memory_budget.unwrap_or(0) as u64)?;
+        w.write_u64::<LittleEndian>(self.fingerprint)?;
+        write_uvarint(w, self.cache.len() as u64)?;
+        for slot in &self.cache {
+            match *slot {
+                Some(ref entry) => {
+                    w.write_u8(1)?;
+                    write_uvarint(w, entry.hits as u64)?;
+                    write_bytes(w, &entry.data)?;
                 }
+                None => w.write_u8(0)?,
             }
-            // fallback to lockup via convolution
-            for hi in 0..haystack.data.len() - (CHUNK_SIZE - 1) {
-                if &haystack.data[hi..hi + CHUNK_SIZE] == chunk {
-                    chunks.push(hi + 1);
-                    continue 'next_chunk;
+        }
+        self.policy.save_state(w)
+    }
+
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError> {
+        read_preamble(r, KIND_CHUNKED)?;
+        let insert_policy = InsertPolicy::load_state(r)?;
+        let encoded = read_uvarint(r)?;
+        let policy_kind =
+            Policy::from_id(r.read_u8()?).ok_or(DsscError::CorruptState("unknown policy"))?;
+        let cache_size = read_uvarint(r)? as usize;
+        if cache_size == 0 {
+            return Err(DsscError::CorruptState("empty cache"));
+        }
+        let memory_budget = match read_uvarint(r)? as usize {
+            0 => None,
+            budget => Some(budget),
+        };
+        let fingerprint = r.read_u64::<LittleEndian>()?;
+        let len = read_uvarint(r)? as usize;
+        if len > cache_size {
+            return Err(DsscError::CorruptState("more entries than the cache holds"));
+        }
+        let mut cache = Vec::new();
+        let mut index = Index::default();
+        let mut memory_usage = 0;
+        for slot in 0..len {
+            cache.push(match r.read_u8()? {
+                0 => None,
+                1 => {
+                    let hits = read_uvarint(r)? as usize;
+                    let data = read_bytes(r)?;
+                    let chunks = first_chunks(&data);
+                    memory_usage += entry_cost(data.len(), chunks.len());
+                    for (chunk, offset) in chunks {
+                        index.entry(chunk).or_default().insert(slot, offset);
+                    }
+                    Some(CacheEntry { hits, data })
                 }
+                _ => return Err(DsscError::CorruptState("bad slot tag")),
+            });
+        }
+        memory_usage += index.len() * KEY_OVERHEAD;
+        if memory_usage > memory_budget.unwrap_or(usize::MAX) {
+            return Err(DsscError::CorruptState("cache over its memory budget"));
+        }
+        let mut policy = policy_kind.build(cache_size);
+        policy.load_state(r)?;
+        let occupied = cache.iter().filter(|slot| slot.is_some()).count();
+        if policy.len() != occupied
+            || !(0..len).all(|slot| cache[slot].is_none() || policy.contains(slot))
+        {
+            return Err(DsscError::CorruptState("policy does not match the cache"));
+        }
+        let vacant = (0..len).filter(|&slot| cache[slot].is_none()).collect();
+        *self = ChunkedCompressor {
+            cache,
+            vacant,
+            index,
+            policy_kind,
+            policy,
+            cache_size,
+            memory_budget,
+            memory_usage,
+            insert_policy,
+            encoded,
+            fingerprint,
+        };
+        Ok(())
+    }
+}
+
+/// For the `MAX_LINES` haystacks sharing the most chunks with the needle,
+/// most shared first, where each chunk of the needle was found in them, plus one, 0 means not
+/// found. Haystacks are counted through the index by the chunks no more than
+/// `MAX_CANDIDATES` of them hold, or by any `MAX_CANDIDATES` holders of each
+/// chunk if every chunk is that common, so a lookup takes time in the length
+/// of the needle rather than the size of the cache.
+fn chunk_match(needle: &[u8], haystacks: &VecCache, index: &Index) -> Vec<(usize, Vec<usize>)> {
+    let chunks = needle.len().div_ceil(CHUNK_SIZE);
+    let holders: Vec<Option<&FnvHashMap<usize, usize>>> = needle
+        .chunks_exact(CHUNK_SIZE)
+        .map(|chunk| index.get(&chunk_key(chunk)))
+        .collect();
+    let mut found: FnvHashMap<usize, usize> = FnvHashMap::default();
+    for slots in holders.iter().flatten() {
+        if slots.len() <= MAX_CANDIDATES {
+            for &slot in slots.keys() {
+                *found.entry(slot).or_default() += 1;
             }
-            chunks.push(0);
         }
-        results.push(chunks);
     }
-    return results;
+    if found.is_empty() {
+        for slots in holders.iter().flatten() {
+            for &slot in slots.keys().take(MAX_CANDIDATES) {
+                *found.entry(slot).or_default() += 1;
+            }
+        }
+    }
+    let mut slots: Vec<(usize, usize)> = found.into_iter().collect();
+    slots.sort_unstable_by_key(|&(slot, count)| (!count, slot));
+    slots
+        .into_iter()
+        .take(MAX_LINES)
+        .map(|(slot, _)| {
+            let haystack = &haystacks[slot].as_ref().expect("indexed an empty slot").data;
+            let mut result: Vec<usize> = Vec::with_capacity(chunks);
+            for (ci, chunk) in needle.chunks(CHUNK_SIZE).enumerate() {
+                // check the chunk following the last chunk first
+                if let Some(&last) = result.last() {
+                    if last != 0 {
+                        let hi = last - 1 + CHUNK_SIZE;
+                        if hi + CHUNK_SIZE < haystack.len() - 1
+                            && &haystack[hi..hi + CHUNK_SIZE] == chunk
+                        {
+                            result.push(hi + 1);
+                            continue;
+                        }
+                    }
+                }
+                let first = holders.get(ci).cloned().flatten().and_then(|slots| slots.get(&slot));
+                result.push(first.map_or(0, |&offset| offset + 1));
+            }
+            (slot, result)
+        })
+        .collect()
 }
 
 #[derive(Debug, PartialEq)]
@@ -192,6 +604,8 @@ enum BlockType {
 
 struct Block {
     block_type: BlockType,
+    /// Cache slot a `Delta` copies from.
+    line: usize,
     needle_off: usize,
     offset: usize,
     len: usize,
@@ -206,7 +620,7 @@ impl fmt::Display for Block {
             self.needle_off,
             self.needle_off + self.len,
             if self.block_type == BlockType::Delta {
-                format!("@{}", self.offset)
+                format!("@{}:{}", self.line, self.offset)
             } else {
                 "".to_string()
             }
@@ -216,12 +630,12 @@ impl fmt::Display for Block {
 
 impl fmt::Debug for Block {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        (self as &fmt::Display).fmt(f)
+        (self as &dyn fmt::Display).fmt(f)
     }
 }
 
 impl Block {
-    fn fit(&mut self, needle: &[u8], haystack: &Vec<u8>, left_bound: usize, right_bound: usize) {
+    fn fit(&mut self, needle: &[u8], haystack: &[u8], left_bound: usize, right_bound: usize) {
         if self.block_type != BlockType::Delta {
             return;
         }
@@ -245,17 +659,35 @@ impl Block {
         self.needle_off -= od;
     }
 
-    fn encode(&self, needle: &[u8], buf: &mut Vec<u8>) {
+    /// Bytes `encode` writes for this block.
+    fn cost(&self, prev_line: Option<usize>) -> usize {
+        let head = match self.block_type {
+            BlockType::Delta if prev_line == Some(self.line) => 1 + uvarint_len(self.offset as u64),
+            BlockType::Delta => uvarint_len(self.line as u64 + 2) + uvarint_len(self.offset as u64),
+            BlockType::Original => 1 + self.len,
+        };
+        head + uvarint_len(self.len as u64)
+    }
+
+    /// Writes the block, tagged 0 for literals, 1 for a `Delta` into the same
+    /// line as the `Delta` before it, or its line plus 2.
+    fn encode(&self, needle: &[u8], prev_line: Option<usize>, buf: &mut Vec<u8>) {
         let mut varint_buf = [0; 10];
         match self.block_type {
             BlockType::Delta => {
-                let offset_len = put_uvarint(&mut varint_buf, (self.offset + 1) as u64);
+                if prev_line == Some(self.line) {
+                    buf.push(1);
+                } else {
+                    let line_len = put_uvarint(&mut varint_buf, (self.line + 2) as u64);
+                    buf.extend_from_slice(&varint_buf[0..line_len]);
+                }
+                let offset_len = put_uvarint(&mut varint_buf, self.offset as u64);
                 buf.extend_from_slice(&varint_buf[0..offset_len]);
                 let len_len = put_uvarint(&mut varint_buf, (self.len) as u64);
                 buf.extend_from_slice(&varint_buf[0..len_len]);
             }
             BlockType::Original => {
-                buf.push(0 as u8);
+                buf.push(0);
                 let len_len = put_uvarint(&mut varint_buf, (self.len) as u64);
                 buf.extend_from_slice(&varint_buf[0..len_len]);
                 buf.extend_from_slice(&needle[self.needle_off..self.needle_off + self.len])
@@ -264,7 +696,7 @@ impl Block {
     }
 }
 
-fn expand_blocks(needle: &[u8], haystack: &Vec<u8>, result: &Vec<usize>) -> Vec<Block> {
+fn expand_blocks(needle: &[u8], haystack: &[u8], line: usize, result: &[usize]) -> Vec<Block> {
     let mut blocks: Vec<Block> = Vec::new();
     let mut ri = 0;
     while ri < result.len() {
@@ -278,9 +710,10 @@ fn expand_blocks(needle: &[u8], haystack: &Vec<u8>, result: &Vec<usize>) -> Vec<
             }
             let mut block = Block {
                 block_type: BlockType::Delta,
+                line,
                 needle_off: bi * CHUNK_SIZE,
-                offset: offset,
-                len: len,
+                offset,
+                len,
             };
             //eprintln!("Before fit {}", block);
             block.fit(
@@ -301,6 +734,7 @@ fn expand_blocks(needle: &[u8], haystack: &Vec<u8>, result: &Vec<usize>) -> Vec<
         } else {
             let block = Block {
                 block_type: BlockType::Original,
+                line: 0,
                 needle_off: blocks
                     .last()
                     .map(|last| last.needle_off + last.len)
@@ -328,7 +762,7 @@ fn expand_blocks(needle: &[u8], haystack: &Vec<u8>, result: &Vec<usize>) -> Vec<
         );
     }
     //println!("blocks {:?}", blocks);
-    if let Some(ref mut last_block) = blocks.last_mut() {
+    if let Some(last_block) = blocks.last_mut() {
         if last_block.block_type == BlockType::Original {
             last_block.len = needle.len() - last_block.needle_off;
         }
@@ -349,3 +783,166 @@ pub fn chunk_test() {
     println!("{:?}", chunk_decompressor(&compressed, &haystacks));
 }
 */
+
+#[test]
+pub fn window_test() {
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let lines: Vec<Vec<u8>> = (0..300)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            format!("{:016x}\n", seed).into_bytes()
+        })
+        .collect();
+    let mut encoder = ChunkedCompressor::builder().cache_size(300).build();
+    let mut decoder = ChunkedCompressor::builder().cache_size(300).build();
+    for line in &lines {
+        let mut buf = Vec::new();
+        encoder.encode(line, &mut buf).unwrap();
+        decoder.decode(&buf, &mut Vec::new()).unwrap();
+    }
+
+    let mut state = Vec::new();
+    decoder.save_state(&mut state).unwrap();
+    let mut restored = ChunkedCompressor::default();
+    restored.load_state(&mut &state[..]).unwrap();
+
+    // slot 200, not inserted, no longer fits in a byte
+    let mut buf = Vec::new();
+    encoder.encode(&lines[200], &mut buf).unwrap();
+    assert_eq!(&buf[..2], &[0x94, 0x03]);
+    let mut out = Vec::new();
+    restored.decode(&buf, &mut out).unwrap();
+    assert_eq!(out, lines[200]);
+    assert_eq!(encoder.fingerprint(), restored.fingerprint());
+}
+
+#[test]
+pub fn multi_line_test() {
+    let templates: [&[u8]; 2] = [
+        b"GET /index.html HTTP/1.1 host=alpha.example.org\n",
+        b"level=warn user_agent=curl/7.68.0 status=404 bytes=512\n",
+    ];
+    let mut encoder = ChunkedCompressor::default();
+    let mut decoder = ChunkedCompressor::default();
+    for template in &templates {
+        let mut buf = Vec::new();
+        encoder.encode(template, &mut buf).unwrap();
+        decoder.decode(&buf, &mut Vec::new()).unwrap();
+    }
+
+    // the start of one line and the end of the other, then back to the first
+    let record = b"GET /index.html HTTP/1.1 user_agent=curl/7.68.0 status=404 alpha.example.org\n";
+    let mut buf = Vec::new();
+    encoder.encode(record, &mut buf).unwrap();
+    assert_eq!(buf, [4, 0, 25, 3, 11, 34, 2, 30, 18]);
+    let mut out = Vec::new();
+    decoder.decode(&buf, &mut out).unwrap();
+    assert_eq!(&out[..], &record[..]);
+    assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+
+    // a change in the middle of a line continues from the same line
+    let record = b"GET /index.html HTTP/2.0 host=alpha.example.org\n";
+    let mut buf = Vec::new();
+    encoder.encode(record, &mut buf).unwrap();
+    assert_eq!(buf, [4, 0, 21, 0, 3, b'2', b'.', b'0', 1, 24, 24]);
+    assert!(decoder.decode(&[2, 0, 4], &mut Vec::new()).is_err());
+}
+
+#[test]
+pub fn roundtrip_test() {
+    let mut seed = 0x853c_49e6_748f_ea9bu64;
+    let mut next = move |n: u64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed % n
+    };
+    let words: [&[u8]; 8] = [b"GET ", b"POST ", b"/api/v1/", b"user=", b"status=200 ", b"\t", b"0", b"\n"];
+    let policies = [Policy::Lru, Policy::LfuAging, Policy::Arc, Policy::Fifo];
+    for round in 0..16 {
+        let cache_size = 1 + next(40) as usize;
+        let budget = super::cache::ENTRY_OVERHEAD + next(4096) as usize;
+        let builder = || {
+            let builder = ChunkedCompressor::builder()
+                .cache_size(cache_size)
+                .policy(policies[round % policies.len()]);
+            if round % 3 == 0 {
+                builder.memory_budget(budget)
+            } else {
+                builder
+            }
+        };
+        let insert_policy = if round % 2 == 0 {
+            InsertPolicy::Threshold(next(10) as f32 / 10.0)
+        } else {
+            InsertPolicy::Every(1 + next(5))
+        };
+        let mut encoder = builder().insert_policy(insert_policy).build();
+        // the decoder follows the frames whatever its own insert policy
+        let mut decoder = builder().build();
+
+        // both sides append to buffers that already hold earlier records
+        let mut frames = Vec::new();
+        let mut decoded = Vec::new();
+        for _ in 0..200 {
+            let mut record = Vec::new();
+            for _ in 0..next(12) {
+                if next(4) == 0 {
+                    record.push(next(256) as u8);
+                } else {
+                    record.extend_from_slice(words[next(words.len() as u64) as usize]);
+                }
+            }
+            let start = frames.len();
+            let clen = encoder.encode(&record, &mut frames).unwrap();
+            assert_eq!(clen, frames.len() - start);
+            let from = decoded.len();
+            let dlen = decoder.decode(&frames[start..], &mut decoded).unwrap();
+            assert_eq!(&decoded[from..], &record[..]);
+            assert_eq!(dlen, record.len());
+            assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+            assert_eq!(encoder.memory_usage(), decoder.memory_usage());
+        }
+        // every distinct chunk in the index is charged once
+        let lines: usize = encoder.cache.iter().flatten()
+            .map(|entry| entry_cost(entry.data.len(), first_chunks(&entry.data).len()))
+            .sum();
+        assert_eq!(encoder.memory_usage(), lines + encoder.index.len() * KEY_OVERHEAD);
+        if round % 3 == 0 {
+            assert!(encoder.memory_usage() <= budget);
+        }
+    }
+}
+
+#[test]
+pub fn corrupt_test() {
+    let line = b"Jan 12 10:00:01 host sshd[100]: Accepted publickey for root\n";
+    let mut encoder = ChunkedCompressor::new(0.5);
+    let mut decoder = ChunkedCompressor::new(0.5);
+    let mut buf = Vec::new();
+    encoder.encode(line, &mut buf).unwrap();
+    decoder.decode(&buf, &mut Vec::new()).unwrap();
+
+    // a valid literal, then an empty slot, a repeat with no line before it,
+    // an offset past the line and an unterminated varint
+    let bad: [&[u8]; 4] = [
+        &[0, 2, b'a', b'b', 9, 0, 1],
+        &[0, 2, b'a', b'b', 1, 0, 1],
+        &[0, 2, b'a', b'b', 2, 100, 1],
+        &[0, 2, b'a', b'b', 0x80],
+    ];
+    for bad in bad.iter() {
+        let mut out = b"earlier record\n".to_vec();
+        assert!(decoder.decode(bad, &mut out).is_err());
+        assert_eq!(&out[..], b"earlier record\n");
+    }
+
+    buf.clear();
+    encoder.encode(line, &mut buf).unwrap();
+    let mut out = Vec::new();
+    decoder.decode(&buf, &mut out).unwrap();
+    assert_eq!(&out[..], &line[..]);
+    assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+}
@@ -1,30 +1,143 @@
+extern crate byteorder;
 extern crate fnv;
 extern crate slab;
 
-use self::fnv::FnvHashMap;
-use self::fnv::FnvHashSet;
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use self::fnv::{FnvHashMap, FnvHashSet, FnvHasher};
 use self::slab::Slab;
 
-use super::varint::{put_uvarint, uvarint};
-use super::Compressor;
+use super::cache::{entry_cost, fingerprint_mix, flag_insert, line_key, split_insert, CachePolicy,
+                   InsertPolicy, Policy, FP_HIT, FP_INSERT, FP_PIN, FP_PROMOTE, FP_REMOVE, FP_RESET,
+                   KEY_OVERHEAD};
+use super::dict::Dictionary;
+use super::entropy::Fields;
+use super::state::{read_bytes, read_preamble, read_slot, write_bytes, write_preamble, write_slot,
+                   KIND_CHUNKMAP};
+use super::varint::{next_uvarint, put_uvarint, read_uvarint, uvarint_len, write_uvarint};
+use super::{Compressor, DsscError};
 
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::convert::TryInto;
 use std::fmt;
-use std::hash::{Hash, Hasher};
+use std::hash::Hasher;
+use std::io::{Read, Write};
+use std::mem;
 
-const EDEN_SIZE: usize = 10;
-const CACHE_SIZE: usize = 255 - EDEN_SIZE;
-const CHUNK_SIZE: usize = 4;
+pub const DEFAULT_EDEN_SIZE: usize = 10;
+pub const DEFAULT_MAIN_SIZE: usize = 245;
+pub const DEFAULT_CHUNK_SIZE: usize = 4;
+pub const MIN_CHUNK_SIZE: usize = 2;
+pub const MAX_CHUNK_SIZE: usize = 64;
+/// Greedy parsing, the longest match at every anchor.
+pub const LEVEL_GREEDY: i32 = 0;
+/// Optimal parsing, the smallest encoding of every record.
+pub const LEVEL_OPTIMAL: i32 = 1;
+/// The optimal parser tries every shorter length of a match up to this one,
+/// longer matches are only taken whole.
+const SUFFICIENT_LEN: usize = 64;
+/// Tag and a one byte length, what a literal run costs on top of its bytes.
+const LITERAL_HEADER: usize = 2;
+/// Matches of a chunk tried per anchor, the most recent ones. Chunks common to
+/// most of a large cache would otherwise make every lookup a scan of it.
+const MAX_CANDIDATES: usize = 256;
+/// Longest `Local` block, so a few bytes of input cannot decode to gigabytes.
+pub const MAX_LOCAL_LEN: usize = 1 << 16;
+const OP_EVICT: u64 = 0;
+const OP_RESET: u64 = 1;
+const OP_PIN: u64 = 2;
+
+/// Chunks of up to 8 bytes are packed into the key as they are, longer ones
+/// are hashed, so matches found through a key still have to be checked. Keys
+/// only depend on the bytes, never on the host's endianness or alignment.
+pub type ChunkKey = u64;
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Match {
     line: u32,
     offset: u32,
+    // of the line, so matches left behind by an evicted line can be told apart
+    generation: u32,
+}
+
+/// Matches of one chunk, oldest first. Removing a line only counts its
+/// matches as stale, they are dropped once they make up half of the list, so
+/// evicting a line does not scan every list it appears in.
+#[derive(Default)]
+struct Matches {
+    list: Vec<Match>,
+    stale: usize,
 }
 
-#[derive(Debug, PartialEq)]
+/// Whether the line `m` was indexed from is still in its slot.
+fn live(entries: &Slab<Entry>, m: &Match) -> bool {
+    entries.get(m.line as usize).is_some_and(|entry| entry.generation == m.generation)
+}
+
+/// Every block starts with a varint tag: 0 is `Original`, followed by the
+/// length and the literal bytes; 1 is `Local`, followed by the length and the
+/// distance back from the end of what the record has decoded so far; anything
+/// higher is `Delta` into cache line `tag - 2`, followed by length and offset.
+/// The first tag of a frame is shifted left by one, its low bit set if the
+/// record goes into the cache. An empty `Original` is a `Command` instead,
+/// followed by its opcode and, for `Evict` and `Pin`, the slot.
+#[derive(Debug, PartialEq, Clone, Copy)]
 enum BlockType {
     Delta { line: usize, offset: usize },
+    /// Copies from earlier in the same record, it may overlap itself.
+    Local { distance: usize },
     Original,
+    Command(Command),
+}
+
+/// Changes to the cache an encoder sends along with a record, see
+/// `ChunkMap::queue`. Decoders apply them in order once the record is
+/// decoded, before it is inserted.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Command {
+    /// Inserts the record whatever the insert policy says. Carried by the
+    /// insert bit, like `NoInsert`, so it costs nothing.
+    Insert,
+    /// Keeps the record out of the cache whatever the insert policy says.
+    NoInsert,
+    /// Drops the line in a slot.
+    Evict(usize),
+    /// Drops every line, pinned ones too.
+    Reset,
+    /// Takes the line in a slot out of both generations, so it stays until
+    /// it is evicted or the cache is reset. It still counts against the
+    /// memory budget.
+    Pin(usize),
+}
+
+impl Command {
+    fn save_state(self, w: &mut dyn Write) -> Result<(), DsscError> {
+        match self {
+            Command::Insert => w.write_u8(0)?,
+            Command::NoInsert => w.write_u8(1)?,
+            Command::Evict(slot) => {
+                w.write_u8(2)?;
+                write_slot(w, slot)?;
+            }
+            Command::Reset => w.write_u8(3)?,
+            Command::Pin(slot) => {
+                w.write_u8(4)?;
+                write_slot(w, slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_state(r: &mut dyn Read) -> Result<Command, DsscError> {
+        match r.read_u8()? {
+            0 => Ok(Command::Insert),
+            1 => Ok(Command::NoInsert),
+            2 => Ok(Command::Evict(read_slot(r)?)),
+            3 => Ok(Command::Reset),
+            4 => Ok(Command::Pin(read_slot(r)?)),
+            _ => Err(DsscError::CorruptState("unknown command")),
+        }
+    }
 }
 
 struct Block {
@@ -47,166 +160,716 @@ impl fmt::Display for Block {
 
 impl fmt::Debug for Block {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        (self as &fmt::Display).fmt(f)
+        (self as &dyn fmt::Display).fmt(f)
     }
 }
 
 impl Block {
+    /// Bytes saved over writing the same bytes as literals.
+    fn saving(&self) -> isize {
+        self.len as isize - self.cost() as isize
+    }
+
+    /// Bytes `encode` writes for this block.
+    fn cost(&self) -> usize {
+        let refs = match self.block_type {
+            BlockType::Delta { line, offset } => {
+                uvarint_len(line as u64 + 2) + uvarint_len(offset as u64)
+            }
+            BlockType::Local { distance } => 1 + uvarint_len(distance as u64),
+            BlockType::Original => 1 + self.len,
+            BlockType::Command(Command::Evict(slot)) | BlockType::Command(Command::Pin(slot)) => {
+                2 + uvarint_len(slot as u64)
+            }
+            BlockType::Command(_) => 2,
+        };
+        refs + uvarint_len(self.len as u64)
+    }
+
     fn encode(&self, needle: &[u8], buf: &mut Vec<u8>) {
         let mut varint_buf = [0; 10];
         match self.block_type {
             BlockType::Delta { line, offset } => {
                 //eprintln!("{},{},{}", line, self.len, offset);
-                let varint_len = put_uvarint(&mut varint_buf, (line + 1) as u64);
+                let varint_len = put_uvarint(&mut varint_buf, (line + 2) as u64);
                 buf.extend_from_slice(&varint_buf[0..varint_len]);
                 let varint_len = put_uvarint(&mut varint_buf, self.len as u64);
                 buf.extend_from_slice(&varint_buf[0..varint_len]);
                 let varint_len = put_uvarint(&mut varint_buf, offset as u64);
                 buf.extend_from_slice(&varint_buf[0..varint_len]);
             }
+            BlockType::Local { distance } => {
+                buf.push(1);
+                let varint_len = put_uvarint(&mut varint_buf, self.len as u64);
+                buf.extend_from_slice(&varint_buf[0..varint_len]);
+                let varint_len = put_uvarint(&mut varint_buf, distance as u64);
+                buf.extend_from_slice(&varint_buf[0..varint_len]);
+            }
             BlockType::Original => {
-                buf.push(0 as u8);
+                buf.push(0);
                 let varint_len = put_uvarint(&mut varint_buf, self.len as u64);
                 buf.extend_from_slice(&varint_buf[0..varint_len]);
                 buf.extend_from_slice(&needle[self.needle_off..self.needle_off + self.len])
             }
+            BlockType::Command(command) => {
+                let (op, slot) = match command {
+                    Command::Evict(slot) => (OP_EVICT, Some(slot)),
+                    Command::Reset => (OP_RESET, None),
+                    Command::Pin(slot) => (OP_PIN, Some(slot)),
+                    // these travel in the insert bit
+                    Command::Insert | Command::NoInsert => return,
+                };
+                buf.extend_from_slice(&[0, 0, op as u8]);
+                if let Some(slot) = slot {
+                    let varint_len = put_uvarint(&mut varint_buf, slot as u64);
+                    buf.extend_from_slice(&varint_buf[0..varint_len]);
+                }
+            }
         }
     }
 
-    fn decode<'a>(buf: &'a [u8], cache: &'a mut ChunkMap) -> (&'a [u8], usize) {
+    /// Parses the rest of a block tagged `line` from the start of `buf` and
+    /// checks it against the live cache without touching it. For `Original`
+    /// blocks `needle_off` points at the literal bytes inside `buf`. `produced`
+    /// is how many bytes the blocks before this one decode to. Commands are
+    /// checked by `ChunkMap::check_commands`, together with the rest of them.
+    fn decode(line: u64, buf: &[u8], cache: &ChunkMap, produced: usize) -> Result<(Block, usize), DsscError> {
         let mut i = 0;
-        let (line, varint_len) = uvarint(&buf);
-        assert!(varint_len > 0);
-        i += varint_len as usize;
-        let (length, varint_len) = uvarint(&buf[i..]);
-        assert!(varint_len > 0);
-        let length = length as usize;
-        i += varint_len as usize;
-        if line == 0 {
-            (&buf[i..i + length], i + length)
+        let length = next_uvarint(buf, &mut i)? as usize;
+        if line == 0 && length == 0 {
+            let command = match next_uvarint(buf, &mut i)? {
+                OP_EVICT => Command::Evict(next_uvarint(buf, &mut i)? as usize),
+                OP_RESET => Command::Reset,
+                OP_PIN => Command::Pin(next_uvarint(buf, &mut i)? as usize),
+                op => return Err(DsscError::UnknownCommand { op }),
+            };
+            let block = Block {
+                block_type: BlockType::Command(command),
+                needle_off: 0,
+                len: 0,
+            };
+            Ok((block, i))
+        } else if line == 1 {
+            let distance = next_uvarint(buf, &mut i)? as usize;
+            if distance == 0 || distance > produced || length > MAX_LOCAL_LEN {
+                return Err(DsscError::BadBackReference { distance, len: length, produced });
+            }
+            let block = Block {
+                block_type: BlockType::Local { distance },
+                needle_off: 0,
+                len: length,
+            };
+            Ok((block, i))
+        } else if line == 0 {
+            if buf.len() - i < length {
+                return Err(DsscError::TruncatedBlock { offset: i, len: length });
+            }
+            let block = Block {
+                block_type: BlockType::Original,
+                needle_off: i,
+                len: length,
+            };
+            Ok((block, i + length))
         } else {
-            let line = (line - 1) as usize;
-            let (offset, varint_len) = uvarint(&buf[i..]);
-            assert!(varint_len > 0);
-            let offset = offset as usize;
-            i += varint_len as usize;
+            let line = (line - 2) as usize;
+            let offset = next_uvarint(buf, &mut i)? as usize;
             //eprintln!("{},{},{}", line, length, offset);
-            cache.entries[line].1 += length;
-            (&cache.entries[line].0[offset..offset + length], i)
+            if line >= cache.capacity() {
+                return Err(DsscError::LineOutOfRange { line });
+            }
+            let entry = cache
+                .entries
+                .get(line)
+                .ok_or(DsscError::UnknownSlot { slot: line })?;
+            if offset > entry.data.len() || entry.data.len() - offset < length {
+                return Err(DsscError::OffsetOutOfRange { line, offset, len: length });
+            }
+            let block = Block {
+                block_type: BlockType::Delta { line, offset },
+                needle_off: 0,
+                len: length,
+            };
+            Ok((block, i))
+        }
+    }
+}
+
+const FIELD_TAG: usize = 0;
+const FIELD_LEN: usize = 1;
+const FIELD_OFFSET: usize = 2;
+const FIELD_DISTANCE: usize = 3;
+const FIELD_LITERAL: usize = 4;
+
+/// Follows the block layout byte by byte: tags, lengths, cache offsets,
+/// local distances and literal bytes each get their own field.
+#[derive(Default)]
+pub struct BlockFields {
+    field: usize,
+    value: u64,
+    shift: u32,
+    tag: u64,
+    // the first tag of a record also carries the insert bit
+    tagged: bool,
+    // the next tag is a command opcode
+    command: bool,
+    literals: u64,
+}
+
+impl Fields for BlockFields {
+    fn count(&self) -> usize {
+        5
+    }
+
+    fn field(&self) -> usize {
+        self.field
+    }
+
+    fn next(&mut self, byte: u8) {
+        if self.field == FIELD_LITERAL {
+            self.literals -= 1;
+            if self.literals == 0 {
+                self.field = FIELD_TAG;
+            }
+            return;
+        }
+        if self.shift < 64 {
+            self.value |= ((byte & 0x7f) as u64) << self.shift;
         }
+        if byte & 0x80 != 0 {
+            self.shift += 7;
+            return;
+        }
+        let value = self.value;
+        self.value = 0;
+        self.shift = 0;
+        self.field = match self.field {
+            FIELD_TAG if self.command => {
+                self.command = false;
+                if value == OP_RESET {
+                    FIELD_TAG
+                } else {
+                    FIELD_OFFSET
+                }
+            }
+            FIELD_TAG => {
+                self.tag = if self.tagged { value } else { split_insert(value).0 };
+                self.tagged = true;
+                FIELD_LEN
+            }
+            FIELD_LEN if self.tag == 0 && value != 0 => {
+                self.literals = value;
+                FIELD_LITERAL
+            }
+            FIELD_LEN if self.tag == 0 => {
+                self.command = true;
+                FIELD_TAG
+            }
+            FIELD_LEN if self.tag == 1 => FIELD_DISTANCE,
+            FIELD_LEN => FIELD_OFFSET,
+            _ => FIELD_TAG,
+        };
     }
+
+    fn reset(&mut self) {
+        *self = BlockFields::default();
+    }
+}
+
+struct Entry {
+    data: Vec<u8>,
+    hits: usize,
+    eden: bool,
+    pinned: bool,
+    generation: u32,
 }
 
+/// History cache of whole lines, indexed by every chunk in them.
+///
+/// New lines wait in a small eden and are dropped first in first out unless
+/// a record references them, in which case they are promoted to the main
+/// generation at the end of that record, so a fresh line is never traded for
+/// an old one. Evictions from the main generation are up to a `CachePolicy`.
+/// Pinned lines belong to neither generation and are only dropped by commands.
 pub struct ChunkMap {
-    map: FnvHashMap<u32, Vec<Match>>,
-    entries: Slab<(Vec<u8>, usize)>,
-    insert_threshold: f32,
+    map: FnvHashMap<ChunkKey, Matches>,
+    entries: Slab<Entry>,
+    // bumped for every line inserted
+    generation: u32,
+    // mirrors the slab free list, most recently freed slot last
+    vacant: Vec<usize>,
+    // eden slots, oldest first
+    eden: VecDeque<usize>,
+    eden_size: usize,
+    main_size: usize,
+    policy_kind: Policy,
+    policy: Box<dyn CachePolicy>,
+    memory_budget: Option<usize>,
+    memory_usage: usize,
+    pinned: usize,
+    // sent with the next record
+    commands: Vec<Command>,
+    insert_policy: InsertPolicy,
+    // records encoded so far, for `InsertPolicy::Every`
+    encoded: u64,
+    chunk_size: usize,
+    level: i32,
+    min_match: usize,
+    fingerprint: u64,
 }
 
-pub fn slice_to_u32(s: &[u8]) -> u32 {
-    assert!(s.len() == 4);
-    unsafe { *(s.as_ptr() as *const u32) }
+pub struct ChunkMapBuilder {
+    insert_policy: InsertPolicy,
+    chunk_size: usize,
+    eden_size: usize,
+    main_size: usize,
+    policy: Policy,
+    memory_budget: Option<usize>,
+    level: i32,
+    min_match: usize,
 }
 
-impl ChunkMap {
-    pub fn new(insert_threshold: f32) -> Self {
+impl ChunkMapBuilder {
+    /// Records that compress worse than this ratio are added to the cache.
+    pub fn insert_threshold(mut self, insert_threshold: f32) -> Self {
+        self.insert_policy = InsertPolicy::Threshold(insert_threshold);
+        self
+    }
+
+    /// Decides which records are added to the cache. Only the encoder uses
+    /// it, frames tell the decoder what to insert.
+    pub fn insert_policy(mut self, insert_policy: InsertPolicy) -> Self {
+        assert!(insert_policy != InsertPolicy::Every(0), "cannot insert every 0th record");
+        self.insert_policy = insert_policy;
+        self
+    }
+
+    /// Length of the anchors matches are looked up by, both ends need the same
+    /// one. Longer anchors keep match lists short on long, repetitive records,
+    /// shorter ones find more matches in short records.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(
+            (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size),
+            "chunk size {} out of range",
+            chunk_size
+        );
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Lines new entries wait in until their first hit, 0 inserts straight
+    /// into the main generation.
+    pub fn eden_size(mut self, eden_size: usize) -> Self {
+        self.eden_size = eden_size;
+        self
+    }
+
+    /// Lines kept once they have been hit.
+    pub fn main_size(mut self, main_size: usize) -> Self {
+        assert!(main_size != 0, "main generation cannot be empty");
+        self.main_size = main_size;
+        self
+    }
+
+    /// Eviction policy of the main generation.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Also evicts lines while the cache would take more than `bytes`, see
+    /// `ChunkMap::memory_usage`. The line count limits still apply, raise them
+    /// to be limited by memory alone.
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// How hard the encoder looks for the smallest encoding, `LEVEL_GREEDY`
+    /// or `LEVEL_OPTIMAL`. Decoders do not care. With the default insert
+    /// threshold optimal parsing saves a few percent at most, as the records
+    /// it shrinks then stay out of the cache; it pays off mainly together
+    /// with an `insert_policy` that inserts regardless of the ratio.
+    pub fn level(mut self, level: i32) -> Self {
+        assert!(
+            (LEVEL_GREEDY..=LEVEL_OPTIMAL).contains(&level),
+            "level {} out of range",
+            level
+        );
+        self.level = level;
+        self
+    }
+
+    /// References shorter than this are written as literals even when they
+    /// would save bytes. Matches are never shorter than the chunk size anyway.
+    pub fn min_match(mut self, min_match: usize) -> Self {
+        self.min_match = min_match;
+        self
+    }
+
+    pub fn build(self) -> ChunkMap {
         ChunkMap {
             map: FnvHashMap::default(),
-            entries: Slab::with_capacity(CACHE_SIZE),
-            insert_threshold,
+            entries: Slab::new(),
+            generation: 0,
+            vacant: Vec::new(),
+            eden: VecDeque::with_capacity(self.eden_size),
+            eden_size: self.eden_size,
+            main_size: self.main_size,
+            policy_kind: self.policy,
+            policy: self.policy.build(self.main_size),
+            memory_budget: self.memory_budget,
+            memory_usage: 0,
+            pinned: 0,
+            commands: Vec::new(),
+            insert_policy: self.insert_policy,
+            encoded: 0,
+            chunk_size: self.chunk_size,
+            level: self.level,
+            min_match: self.min_match,
+            fingerprint: 0,
+        }
+    }
+}
+
+fn line_cost(len: usize, chunk_size: usize) -> usize {
+    entry_cost(len, (len + 1).saturating_sub(chunk_size))
+}
+
+pub fn chunk_key(s: &[u8]) -> ChunkKey {
+    if s.len() <= 8 {
+        s.iter().fold(0, |key, &b| key << 8 | b as u64)
+    } else {
+        let mut hasher = FnvHasher::default();
+        hasher.write(s);
+        hasher.finish()
+    }
+}
+
+impl ChunkMap {
+    pub fn new(insert_threshold: f32) -> Self {
+        ChunkMap::builder().insert_threshold(insert_threshold).build()
+    }
+
+    pub fn builder() -> ChunkMapBuilder {
+        ChunkMapBuilder {
+            insert_policy: InsertPolicy::Threshold(0.5),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            eden_size: DEFAULT_EDEN_SIZE,
+            main_size: DEFAULT_MAIN_SIZE,
+            policy: Policy::default(),
+            memory_budget: None,
+            level: LEVEL_GREEDY,
+            min_match: 0,
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Slots a frame can reference, eden, main and pinned lines together, or
+    /// more while lines inserted next to pins that have since been evicted
+    /// hold on to their slots.
+    pub fn capacity(&self) -> usize {
+        let lines = self.eden_size.saturating_add(self.main_size).saturating_add(self.pinned);
+        lines.max(self.entries.len() + self.vacant.len())
+    }
+
+    /// Sends `command` with the next record encoded. Neither end applies it
+    /// before that record, so until then `capacity`, `memory_usage` and the
+    /// fingerprint describe the cache without it; a saved state keeps it
+    /// queued. Fails if the command refers to a slot that holds no line by
+    /// then.
+    pub fn queue(&mut self, command: Command) -> Result<(), DsscError> {
+        self.commands.push(command);
+        let checked = self.check_commands(&self.commands);
+        if checked.is_err() {
+            self.commands.pop();
+        }
+        checked
+    }
+
+    /// Checks that `commands` apply in order to the cache as it is.
+    fn check_commands(&self, commands: &[Command]) -> Result<(), DsscError> {
+        let mut evicted = Vec::new();
+        let mut reset = false;
+        for &command in commands {
+            match command {
+                Command::Evict(slot) | Command::Pin(slot) => {
+                    if reset || evicted.contains(&slot) || !self.entries.contains(slot) {
+                        return Err(DsscError::UnknownSlot { slot });
+                    }
+                    if command == Command::Evict(slot) {
+                        evicted.push(slot);
+                    }
+                }
+                Command::Reset => reset = true,
+                Command::Insert | Command::NoInsert => {}
+            }
         }
+        Ok(())
     }
-    fn insert(&mut self, entry: Vec<u8>) {
-        if self.entries.len() == CACHE_SIZE {
-            let (i, _) = self.entries.iter().map(|x| x.1).enumerate().min().unwrap();
-            self.remove(i);
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::Evict(slot) => {
+                self.remove(slot);
+            }
+            Command::Reset => {
+                self.map.clear();
+                self.entries.clear();
+                self.vacant.clear();
+                self.eden.clear();
+                self.policy = self.policy_kind.build(self.main_size);
+                self.memory_usage = 0;
+                self.pinned = 0;
+                self.fingerprint = fingerprint_mix(self.fingerprint, FP_RESET, 0, &[]);
+            }
+            Command::Pin(slot) => {
+                if self.entries[slot].pinned {
+                    return;
+                }
+                if self.entries[slot].eden {
+                    self.eden.retain(|&eden_slot| eden_slot != slot);
+                    self.entries[slot].eden = false;
+                } else {
+                    self.policy.on_remove(slot);
+                }
+                self.entries[slot].pinned = true;
+                self.pinned += 1;
+                self.fingerprint = fingerprint_mix(self.fingerprint, FP_PIN, slot, &[]);
+            }
+            Command::Insert | Command::NoInsert => {}
         }
-        let index = self.entries.insert((entry.clone(), 0));
-        let ref mut map = self.map;
-        for (ci, c) in entry.windows(4).enumerate() {
-            let ic = slice_to_u32(c);
-            map.entry(ic).or_insert(Vec::new()).push(Match {
+    }
+
+    fn cost(&self, len: usize) -> usize {
+        line_cost(len, self.chunk_size)
+    }
+
+    fn insert(&mut self, data: Vec<u8>, eden: bool) {
+        let eden = eden && self.eden_size != 0;
+        let key = line_key(&data);
+        if eden {
+            if self.eden.len() == self.eden_size {
+                let oldest = self.eden[0];
+                self.remove(oldest);
+            }
+        } else {
+            self.make_room_in_main(key);
+        }
+        if let Some(budget) = self.memory_budget {
+            let cost = self.cost(data.len());
+            let keys: FnvHashSet<ChunkKey> = data.windows(self.chunk_size).map(chunk_key).collect();
+            if cost + keys.len() * KEY_OVERHEAD > budget {
+                return;
+            }
+            // evict from the main generation first, eden lines still get their chance
+            while self.memory_usage + cost + self.new_keys(&keys) * KEY_OVERHEAD > budget {
+                let victim = if !self.policy.is_empty() {
+                    self.policy.victim(key)
+                } else if let Some(&oldest) = self.eden.front() {
+                    oldest
+                } else {
+                    // only pinned lines left
+                    return;
+                };
+                self.remove(victim);
+            }
+        }
+        self.memory_usage += self.cost(data.len());
+        self.generation = self.generation.wrapping_add(1);
+        let index = self.entries.insert(Entry {
+            data,
+            hits: 0,
+            eden,
+            pinned: false,
+            generation: self.generation,
+        });
+        if let Some(slot) = self.vacant.pop() {
+            debug_assert_eq!(slot, index, "slab reused an unexpected slot");
+        }
+        if eden {
+            self.eden.push_back(index);
+        } else {
+            self.policy.on_insert(index, key);
+        }
+        self.fingerprint =
+            fingerprint_mix(self.fingerprint, FP_INSERT, index, &self.entries[index].data);
+        self.index(index);
+    }
+    /// How many of `keys` the index does not hold yet.
+    fn new_keys(&self, keys: &FnvHashSet<ChunkKey>) -> usize {
+        keys.iter().filter(|key| !self.map.contains_key(key)).count()
+    }
+    fn make_room_in_main(&mut self, key: u64) {
+        if self.entries.len() - self.eden.len() - self.pinned < self.main_size {
+            return;
+        }
+        let victim = self.policy.victim(key);
+        debug_assert!(!self.entries[victim].eden, "policy picked an eden line");
+        self.remove(victim);
+    }
+    /// Moves every eden line hit by the last record into the main generation.
+    fn promote(&mut self) {
+        let mut i = 0;
+        while i < self.eden.len() {
+            let slot = self.eden[i];
+            if self.entries[slot].hits == 0 {
+                i += 1;
+                continue;
+            }
+            let key = line_key(&self.entries[slot].data);
+            self.make_room_in_main(key);
+            self.eden.remove(i);
+            self.entries[slot].eden = false;
+            self.policy.on_insert(slot, key);
+            self.policy.on_hit(slot, self.entries[slot].hits);
+            self.fingerprint = fingerprint_mix(self.fingerprint, FP_PROMOTE, slot, &[]);
+        }
+    }
+    fn index(&mut self, index: usize) {
+        let map = &mut self.map;
+        let entry = &self.entries[index];
+        let mut keys = 0;
+        for (ci, c) in entry.data.windows(self.chunk_size).enumerate() {
+            let matches = map.entry(chunk_key(c)).or_insert_with(|| {
+                keys += 1;
+                Matches::default()
+            });
+            matches.list.push(Match {
                 line: index as u32,
                 offset: ci as u32,
+                generation: entry.generation,
             });
         }
+        self.memory_usage += keys * KEY_OVERHEAD;
     }
+    /// Empties a slot. Its matches stay in the index until their lists are
+    /// compacted, but no longer count against `memory_usage`: a restored
+    /// cache has none of them, and has to evict the same lines.
     fn remove(&mut self, entry_index: usize) -> Vec<u8> {
         let entry = self.entries.remove(entry_index);
-        for c in entry.0.windows(4) {
-            let ic = slice_to_u32(c);
-            self.map.get_mut(&ic).map(|v| {
-                v.retain(|m| m.line != entry_index as u32)
-            });
+        self.memory_usage -= self.cost(entry.data.len());
+        if entry.eden {
+            self.eden.retain(|&slot| slot != entry_index);
+        } else if entry.pinned {
+            self.pinned -= 1;
+        } else {
+            self.policy.on_remove(entry_index);
         }
-        entry.0
-    }
-}
-
-fn differs_at(a: &[u8], b: &[u8]) -> usize {
-    let max = a.len().min(b.len());
-    let ap = a.as_ptr() as *const usize;
-    let bp = b.as_ptr() as *const usize;
-    let mut in8 = 0;
-    while in8 < max / 8 && unsafe { *ap.offset(in8 as isize) == *bp.offset(in8 as isize) } {
-        in8 += 1;
-    }
-    let mut i = in8 * 8;
-    while i < max && a[i] == b[i] {
-        i += 1;
+        self.vacant.push(entry_index);
+        self.fingerprint = fingerprint_mix(self.fingerprint, FP_REMOVE, entry_index, &[]);
+        for c in entry.data.windows(self.chunk_size) {
+            self.map.get_mut(&chunk_key(c)).expect("line was not indexed").stale += 1;
+        }
+        // compact only once all are counted, a chunk can repeat within the line
+        for c in entry.data.windows(self.chunk_size) {
+            let key = chunk_key(c);
+            let entries = &self.entries;
+            let empty = match self.map.get_mut(&key) {
+                Some(ref mut matches) if matches.stale * 2 > matches.list.len() => {
+                    matches.list.retain(|m| live(entries, m));
+                    matches.stale = 0;
+                    matches.list.is_empty()
+                }
+                _ => continue,
+            };
+            if empty {
+                self.map.remove(&key);
+                self.memory_usage -= KEY_OVERHEAD;
+            }
+        }
+        entry.data
     }
-    i
-}
-
-fn differs_back(a: &[u8], b: &[u8]) -> usize {
-    let al = a.len();
-    let bl = b.len();
-    let max = al.min(bl);
-    let mut i = 1;
-    while i < max && a[al - i] == b[bl - i] {
-        i += 1;
+    fn hit(&mut self, line: usize, len: usize) {
+        self.entries[line].hits += len;
+        if !self.entries[line].eden && !self.entries[line].pinned {
+            self.policy.on_hit(line, len);
+        }
+        let len = (len as u64).to_le_bytes();
+        self.fingerprint = fingerprint_mix(self.fingerprint, FP_HIT, line, &len);
     }
-    i - 1
-}
 
-impl Compressor for ChunkMap {
-    fn encode(&mut self, needle: &[u8], buf: &mut Vec<u8>) {
-        let chunks: Vec<u32> = needle
-            .chunks(4)
-            .filter(|c| c.len() == 4)
-            .map(|c| slice_to_u32(c))
-            .collect();
+    /// Takes the longest match at every anchor, extended back over the bytes
+    /// since the last block.
+    fn parse_greedy(&mut self, needle: &[u8], buf: &mut Vec<u8>) {
+        let chunk_size = self.chunk_size;
+        let chunks: Vec<ChunkKey> = needle.chunks_exact(chunk_size).map(chunk_key).collect();
 
-        let old_buf_len = buf.len();
+        // where each chunk of the needle was last seen, for Local blocks
+        let mut local: FnvHashMap<ChunkKey, usize> = FnvHashMap::default();
+        let mut indexed = 0;
 
         let mut ci = 0;
         let mut last_end = 0;
         while ci < chunks.len() {
-            let m = self.map.get(&chunks[ci]);
-            if m.is_none() || m.unwrap().len() == 0 {
-                ci += 1;
-                continue;
+            let start = ci * chunk_size;
+            while indexed < start {
+                local.insert(chunk_key(&needle[indexed..indexed + chunk_size]), indexed);
+                indexed += 1;
             }
-            let matches = m.unwrap();
-            let block = matches
-                .iter()
-                .map(|m| {
-                    let line = &self.entries[m.line as usize].0;
-                    let diff_back =
-                        differs_back(&needle[last_end..ci * 4], &line[..m.offset as usize]);
-                    let diff_forward =
-                        differs_at(&needle[ci * 4 + 4..], &line[4 + m.offset as usize..]);
-                    Block {
-                        block_type: BlockType::Delta {
-                            line: m.line as usize,
-                            offset: m.offset as usize - diff_back,
-                        },
-                        needle_off: ci * 4 - diff_back,
-                        len: diff_forward + 4 + diff_back,
-                    }
+            let cached = self.map.get(&chunks[ci]).and_then(|matches| {
+                matches
+                    .list
+                    .iter()
+                    .rev()
+                    .filter(|m| live(&self.entries, m))
+                    .take(MAX_CANDIDATES)
+                    .filter_map(|m| {
+                        let line = &self.entries[m.line as usize].data;
+                        let diff_forward =
+                            differs_at(&needle[start..], &line[m.offset as usize..]);
+                        if diff_forward < chunk_size {
+                            // hashed key collided with a different chunk
+                            return None;
+                        }
+                        let diff_back =
+                            differs_back(&needle[last_end..start], &line[..m.offset as usize]);
+                        Some(Block {
+                            block_type: BlockType::Delta {
+                                line: m.line as usize,
+                                offset: m.offset as usize - diff_back,
+                            },
+                            needle_off: start - diff_back,
+                            len: diff_forward + diff_back,
+                        })
+                    })
+                    // the most saving, the most recent of those
+                    .min_by_key(|block| Reverse(block.saving()))
+            });
+            let local = local.get(&chunks[ci]).and_then(|&from| {
+                let diff_forward = differs_at(&needle[start..], &needle[from..]);
+                if diff_forward < chunk_size {
+                    return None;
+                }
+                let diff_back = differs_back(&needle[last_end..start], &needle[..from]);
+                Some(Block {
+                    block_type: BlockType::Local { distance: start - from },
+                    needle_off: start - diff_back,
+                    len: (diff_forward + diff_back).min(MAX_LOCAL_LEN),
                 })
-                .max_by(|a, b| a.len.cmp(&b.len))
-                .unwrap();
+            });
+            let block = match (cached, local) {
+                (Some(cached), Some(local)) => {
+                    Some(if local.saving() > cached.saving() { local } else { cached })
+                }
+                (cached, local) => cached.or(local),
+            };
+            // a reference in the middle of literals also costs the header of the run after it
+            let profitable = |block: &Block| {
+                let splits =
+                    block.needle_off != last_end && block.needle_off + block.len != needle.len();
+                let penalty = if splits { LITERAL_HEADER } else { 0 };
+                block.len >= self.min_match && block.saving() > penalty as isize
+            };
+            let block = match block {
+                Some(ref block) if profitable(block) => block,
+                _ => {
+                    ci += 1;
+                    continue;
+                }
+            };
 
             if last_end != block.needle_off {
                 Block {
@@ -216,11 +879,11 @@ impl Compressor for ChunkMap {
                 }.encode(needle, buf);
             }
 
-            ci += ((block.len + 3) & !0x03) / 4;
+            ci += block.len.div_ceil(chunk_size);
             // it was last.needle_off + last.len -1, but still works, dunno why.
             last_end = block.needle_off + block.len;
             if let BlockType::Delta { line, offset: _ } = block.block_type {
-                self.entries[line].1 += block.len;
+                self.hit(line, block.len);
             }
             block.encode(needle, buf);
         }
@@ -232,28 +895,457 @@ impl Compressor for ChunkMap {
                 len: needle.len() - last_end,
             }.encode(needle, buf);
         }
+    }
+
+    /// Finds the smallest encoding of the record. Matches are looked up at every
+    /// byte, and the cheapest path from the start of the record to its end is
+    /// found over block boundaries. A literal run is charged byte by byte as it
+    /// grows, including the bytes its length varint grows by.
+    fn parse_optimal(&mut self, needle: &[u8], buf: &mut Vec<u8>) {
+        let chunk_size = self.chunk_size;
+        let n = needle.len();
+        // cheapest encoding of needle[..i] with a block ending at i, and that block
+        let mut cost = vec![usize::MAX; n + 1];
+        let mut step: Vec<Option<Block>> = (0..=n).map(|_| None).collect();
+        // cheapest encoding of needle[..i] inside a literal run, and where the run starts
+        let mut run_cost = vec![usize::MAX; n + 1];
+        let mut run_start = vec![0; n + 1];
+        let mut local: FnvHashMap<ChunkKey, usize> = FnvHashMap::default();
+        cost[0] = 0;
+        for i in 0..=n {
+            if run_cost[i] < cost[i] {
+                cost[i] = run_cost[i];
+                step[i] = Some(Block {
+                    block_type: BlockType::Original,
+                    needle_off: run_start[i],
+                    len: i - run_start[i],
+                });
+            }
+            if i == n {
+                break;
+            }
+            // a new run costs its tag and length, a longer one what its length grows by
+            let open = cost[i] + 3;
+            let run = (i - run_start[i]) as u64;
+            let extend = run_cost[i]
+                .saturating_add(1 + uvarint_len(run + 1) - uvarint_len(run));
+            if open <= extend {
+                run_cost[i + 1] = open;
+                run_start[i + 1] = i;
+            } else {
+                run_cost[i + 1] = extend;
+                run_start[i + 1] = run_start[i];
+            }
+
+            if i + chunk_size > n {
+                continue;
+            }
+            let key = chunk_key(&needle[i..i + chunk_size]);
+            let mut candidates = Vec::new();
+            if let Some(matches) = self.map.get(&key) {
+                // the longest match, and the cheapest reference among equally long
+                // ones, the oldest of those
+                let best = matches
+                    .list
+                    .iter()
+                    .rev()
+                    .filter(|m| live(&self.entries, m))
+                    .take(MAX_CANDIDATES)
+                    .filter_map(|m| {
+                        let line = &self.entries[m.line as usize].data;
+                        let len = differs_at(&needle[i..], &line[m.offset as usize..]);
+                        if len < chunk_size {
+                            return None;
+                        }
+                        let refs = uvarint_len(m.line as u64 + 2) + uvarint_len(m.offset as u64);
+                        Some((len, refs, m))
+                    })
+                    .max_by_key(|&(len, refs, _)| (len, Reverse(refs)));
+                if let Some((len, refs, m)) = best {
+                    let block_type = BlockType::Delta {
+                        line: m.line as usize,
+                        offset: m.offset as usize,
+                    };
+                    candidates.push((block_type, len, refs));
+                }
+            }
+            if let Some(&from) = local.get(&key) {
+                let len = differs_at(&needle[i..], &needle[from..]).min(MAX_LOCAL_LEN);
+                if len >= chunk_size {
+                    let distance = i - from;
+                    let refs = 1 + uvarint_len(distance as u64);
+                    candidates.push((BlockType::Local { distance }, len, refs));
+                }
+            }
+            local.insert(key, i);
 
+            for (block_type, len, refs) in candidates {
+                let shorter = chunk_size.max(self.min_match)..=len.min(SUFFICIENT_LEN);
+                let whole = Some(len).filter(|&len| len > SUFFICIENT_LEN && len >= self.min_match);
+                for l in shorter.chain(whole) {
+                    let c = cost[i] + refs + uvarint_len(l as u64);
+                    if c < cost[i + l] {
+                        cost[i + l] = c;
+                        step[i + l] = Some(Block {
+                            block_type,
+                            needle_off: i,
+                            len: l,
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut end = n;
+        while end > 0 {
+            let block = step[end].take().unwrap();
+            end = block.needle_off;
+            blocks.push(block);
+        }
+        for block in blocks.into_iter().rev() {
+            if let BlockType::Delta { line, offset: _ } = block.block_type {
+                self.hit(line, block.len);
+            }
+            block.encode(needle, buf);
+        }
+    }
+}
+
+/// Compares a word at a time; the first differing byte of a word is its
+/// lowest set byte once both are read little endian, whatever the host is.
+fn differs_at(a: &[u8], b: &[u8]) -> usize {
+    let max = a.len().min(b.len());
+    let (a, b) = (&a[..max], &b[..max]);
+    let mut i = 0;
+    for (wa, wb) in a.chunks_exact(8).zip(b.chunks_exact(8)) {
+        let x = u64::from_le_bytes(wa.try_into().unwrap())
+            ^ u64::from_le_bytes(wb.try_into().unwrap());
+        if x != 0 {
+            return i + (x.trailing_zeros() / 8) as usize;
+        }
+        i += 8;
+    }
+    while i < max && a[i] == b[i] {
+        i += 1;
+    }
+    i
+}
+
+fn differs_back(a: &[u8], b: &[u8]) -> usize {
+    let al = a.len();
+    let bl = b.len();
+    let max = al.min(bl);
+    let mut i = 1;
+    while i < max && a[al - i] == b[bl - i] {
+        i += 1;
+    }
+    i - 1
+}
+
+impl Compressor for ChunkMap {
+    fn encode(&mut self, needle: &[u8], buf: &mut Vec<u8>) -> Result<usize, DsscError> {
+        let old_buf_len = buf.len();
+        if self.level == LEVEL_OPTIMAL {
+            self.parse_optimal(needle, buf);
+        } else {
+            self.parse_greedy(needle, buf);
+        }
+
+        let mut insert = self
+            .insert_policy
+            .decide(self.encoded, buf.len() - old_buf_len, needle.len());
+        self.encoded += 1;
+        let commands = mem::take(&mut self.commands);
+        for &command in &commands {
+            match command {
+                Command::Insert => insert = true,
+                Command::NoInsert => insert = false,
+                _ => {}
+            }
+            Block {
+                block_type: BlockType::Command(command),
+                needle_off: 0,
+                len: 0,
+            }.encode(needle, buf);
+        }
+        let insert = flag_insert(buf, old_buf_len, insert);
         let clen = buf.len() - old_buf_len;
-        let cr = clen as f32 / needle.len() as f32;
-        if cr > self.insert_threshold {
-            self.insert(needle.to_vec());
+        for command in commands {
+            self.apply(command);
+        }
+        self.promote();
+        if insert {
+            self.insert(needle.to_vec(), true);
         }
+        Ok(clen)
     }
 
-    fn decode(&mut self, mut in_buf: &[u8], out_buf: &mut Vec<u8>) {
+    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
         let old_buf_len = out_buf.len();
         let in_buf_len = in_buf.len();
-        while in_buf.len() != 0 {
-            let (data, size) = Block::decode(in_buf, self);
-            out_buf.extend_from_slice(data);
-            in_buf = &in_buf[size..];
+        // validate the whole frame first, so a corrupt one leaves the cache as it was
+        let mut blocks = Vec::new();
+        let mut produced = 0;
+        let mut insert = false;
+        let mut i = 0;
+        while i < in_buf_len {
+            let first = i == 0;
+            let mut tag = next_uvarint(in_buf, &mut i)?;
+            if first {
+                let (block_tag, flag) = split_insert(tag);
+                tag = block_tag;
+                insert = flag;
+            }
+            let (mut block, size) = Block::decode(tag, &in_buf[i..], self, produced)?;
+            block.needle_off += i;
+            produced += block.len;
+            blocks.push(block);
+            i += size;
+        }
+        let commands: Vec<Command> = blocks
+            .iter()
+            .filter_map(|block| match block.block_type {
+                BlockType::Command(command) => Some(command),
+                _ => None,
+            })
+            .collect();
+        self.check_commands(&commands)?;
+        for block in blocks {
+            match block.block_type {
+                BlockType::Original => out_buf
+                    .extend_from_slice(&in_buf[block.needle_off..block.needle_off + block.len]),
+                BlockType::Delta { line, offset } => {
+                    self.hit(line, block.len);
+                    out_buf.extend_from_slice(&self.entries[line].data[offset..offset + block.len]);
+                }
+                BlockType::Local { distance } => {
+                    // byte by byte, the copy may overlap the bytes it produces
+                    let from = out_buf.len() - distance;
+                    for k in from..from + block.len {
+                        let byte = out_buf[k];
+                        out_buf.push(byte);
+                    }
+                }
+                BlockType::Command(_) => {}
+            }
         }
         let dlen = out_buf.len() - old_buf_len;
-        let cr = in_buf_len as f32 / dlen as f32;
-        if cr > self.insert_threshold {
+        for command in commands {
+            self.apply(command);
+        }
+        self.promote();
+        if insert {
             //eprintln!("Inserting {}", cr);
-            self.insert(out_buf[old_buf_len..].to_vec());
+            self.insert(out_buf[old_buf_len..].to_vec(), true);
+        }
+        Ok(dlen)
+    }
+
+    fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Cached lines plus one `MATCH_OVERHEAD` per indexed chunk, one
+    /// `KEY_OVERHEAD` per distinct chunk in the index and one `ENTRY_OVERHEAD`
+    /// per line.
+    fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    fn fields(&self) -> Box<dyn Fields> {
+        Box::new(BlockFields::default())
+    }
+
+    fn prime(&mut self, dict: &Dictionary) -> Result<(), DsscError> {
+        for record in &dict.records {
+            self.insert(record.clone(), false);
         }
+        Ok(())
+    }
+
+    /// Slots are written in key order so the restored slab hands out the same
+    /// keys; the match index is rebuilt from the lines on load.
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        write_preamble(w, KIND_CHUNKMAP)?;
+        self.insert_policy.save_state(w)?;
+        write_uvarint(w, self.encoded)?;
+        write_uvarint(w, self.chunk_size as u64)?;
+        w.write_u8(self.level as u8)?;
+        write_uvarint(w, self.min_match as u64)?;
+        write_uvarint(w, self.eden_size as u64)?;
+        write_uvarint(w, self.main_size as u64)?;
+        w.write_u8(self.policy_kind.id())?;
+        write_uvarint(w, self.memory_budget.unwrap_or(0) as u64)?;
+        w.write_u64::<LittleEndian>(self.fingerprint)?;
+        let slots = self.entries.len() + self.vacant.len();
+        write_uvarint(w, slots as u64)?;
+        for slot in 0..slots {
+            match self.entries.get(slot) {
+                Some(entry) => {
+                    w.write_u8(if entry.pinned { 2 } else { 1 })?;
+                    write_uvarint(w, entry.hits as u64)?;
+                    write_bytes(w, &entry.data)?;
+                }
+                None => w.write_u8(0)?,
+            }
+        }
+        write_uvarint(w, self.vacant.len() as u64)?;
+        for &slot in &self.vacant {
+            write_slot(w, slot)?;
+        }
+        write_uvarint(w, self.eden.len() as u64)?;
+        for &slot in &self.eden {
+            write_slot(w, slot)?;
+        }
+        self.policy.save_state(w)?;
+        write_uvarint(w, self.commands.len() as u64)?;
+        for &command in &self.commands {
+            command.save_state(w)?;
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError> {
+        read_preamble(r, KIND_CHUNKMAP)?;
+        let insert_policy = InsertPolicy::load_state(r)?;
+        let encoded = read_uvarint(r)?;
+        let chunk_size = read_uvarint(r)? as usize;
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(DsscError::CorruptState("chunk size out of range"));
+        }
+        let level = r.read_u8()? as i32;
+        if !(LEVEL_GREEDY..=LEVEL_OPTIMAL).contains(&level) {
+            return Err(DsscError::CorruptState("level out of range"));
+        }
+        let min_match = read_uvarint(r)? as usize;
+        let eden_size = read_uvarint(r)? as usize;
+        let main_size = read_uvarint(r)? as usize;
+        if main_size == 0 {
+            return Err(DsscError::CorruptState("empty main generation"));
+        }
+        let policy_kind =
+            Policy::from_id(r.read_u8()?).ok_or(DsscError::CorruptState("unknown policy"))?;
+        let memory_budget = match read_uvarint(r)? as usize {
+            0 => None,
+            budget => Some(budget),
+        };
+        let fingerprint = r.read_u64::<LittleEndian>()?;
+        // pins can leave the slab wider than the generations, so the slots
+        // are only bounded by the input
+        let slots = read_uvarint(r)? as usize;
+        let mut lines = Vec::new();
+        for _ in 0..slots {
+            lines.push(match r.read_u8()? {
+                0 => None,
+                tag @ 1..=2 => {
+                    let hits = read_uvarint(r)? as usize;
+                    Some(Entry {
+                        data: read_bytes(r)?,
+                        hits,
+                        eden: false,
+                        pinned: tag == 2,
+                        generation: 0,
+                    })
+                }
+                _ => return Err(DsscError::CorruptState("bad slot tag")),
+            });
+        }
+        let pinned = lines.iter().flatten().filter(|entry| entry.pinned).count();
+        let mut vacant = Vec::new();
+        for _ in 0..read_uvarint(r)? {
+            let slot = read_slot(r)?;
+            if slot >= slots || lines[slot].is_some() || vacant.contains(&slot) {
+                return Err(DsscError::CorruptState("bad free list"));
+            }
+            vacant.push(slot);
+        }
+        let occupied = lines.iter().filter(|l| l.is_some()).count();
+        if vacant.len() + occupied != slots {
+            return Err(DsscError::CorruptState("bad free list"));
+        }
+        let mut eden = VecDeque::new();
+        for _ in 0..read_uvarint(r)? {
+            let slot = read_slot(r)?;
+            match lines.get_mut(slot) {
+                Some(&mut Some(ref mut entry)) if !entry.eden && !entry.pinned => entry.eden = true,
+                _ => return Err(DsscError::CorruptState("bad eden list")),
+            }
+            eden.push_back(slot);
+        }
+        if eden.len() > eden_size || occupied - eden.len() - pinned > main_size {
+            return Err(DsscError::CorruptState("generation over its size"));
+        }
+        let mut policy = policy_kind.build(main_size);
+        policy.load_state(r)?;
+        let mut main = lines.iter().enumerate().filter(|&(_, line)| match *line {
+            Some(ref entry) => !entry.eden && !entry.pinned,
+            None => false,
+        });
+        if policy.len() != occupied - eden.len() - pinned
+            || !main.all(|(slot, _)| policy.contains(slot))
+        {
+            return Err(DsscError::CorruptState("policy does not match the cache"));
+        }
+        let mut commands = Vec::new();
+        for _ in 0..read_uvarint(r)? {
+            commands.push(Command::load_state(r)?);
+        }
+
+        let memory_usage = lines
+            .iter()
+            .flatten()
+            .map(|entry| line_cost(entry.data.len(), chunk_size))
+            .sum();
+
+        // fill every slot, then free the vacant ones in the order they were freed
+        let mut entries = Slab::with_capacity(slots);
+        for line in lines.iter_mut() {
+            entries.insert(line.take().unwrap_or(Entry {
+                data: Vec::new(),
+                hits: 0,
+                eden: false,
+                pinned: false,
+                generation: 0,
+            }));
+        }
+        for &slot in &vacant {
+            entries.remove(slot);
+        }
+        let mut map = ChunkMap {
+            map: FnvHashMap::default(),
+            entries,
+            generation: 0,
+            vacant,
+            eden,
+            eden_size,
+            main_size,
+            policy_kind,
+            policy,
+            memory_budget,
+            memory_usage,
+            pinned,
+            commands,
+            insert_policy,
+            encoded,
+            chunk_size,
+            level,
+            min_match,
+            fingerprint,
+        };
+        let occupied: Vec<usize> = map.entries.iter().map(|(slot, _)| slot).collect();
+        for slot in occupied {
+            map.index(slot);
+        }
+        // the index is only known once it is rebuilt
+        if map.memory_usage > memory_budget.unwrap_or(usize::MAX) {
+            return Err(DsscError::CorruptState("cache over its memory budget"));
+        }
+        if map.check_commands(&map.commands).is_err() {
+            return Err(DsscError::CorruptState("queued command on an empty slot"));
+        }
+        *self = map;
+        Ok(())
     }
 }
 /*
@@ -275,5 +1367,428 @@ pub fn nchunk_test() {
 pub fn diff_test() {
     let a = b"helloworls";
     let b = b"helloworld";
-    println!("{:?}", differs_at(a, b));
+    assert_eq!(differs_at(a, b), 9);
+    // every length and starting alignment, with the difference in every byte
+    let base: Vec<u8> = (0..40).collect();
+    for start in 0..8 {
+        for at in start..base.len() {
+            let mut other = base.clone();
+            other[at] ^= 0x80;
+            assert_eq!(differs_at(&base[start..], &other[start..]), at - start);
+        }
+        assert_eq!(differs_at(&base[start..], &base[start..30]), 30 - start);
+    }
+}
+
+#[test]
+pub fn corrupt_test() {
+    let mut encoder = ChunkMap::new(0.5);
+    let mut decoder = ChunkMap::new(0.5);
+    let mut buf = Vec::new();
+    encoder.encode(b"Hello Worlds", &mut buf).unwrap();
+    let mut out = Vec::new();
+    decoder.decode(&buf, &mut out).unwrap();
+    assert_eq!(&out[..], b"Hello Worlds");
+
+    // truncated literal, reference to an empty slot and an unterminated varint
+    for bad in [&[0u8, 20, b'H'][..], &[9, 4, 0], &[1, 0x80]].iter() {
+        assert!(decoder.decode(bad, &mut Vec::new()).is_err());
+    }
+}
+
+#[test]
+pub fn fuzz_corpus_test() {
+    use std::fs;
+    let samples = [
+        &b"Jan 12 10:00:01 host sshd[100]: Accepted publickey for root\n"[..],
+        b"Jan 12 10:00:02 host sshd[101]: Accepted publickey for admin\n",
+        b"Jan 12 10:00:03 host cron[7]: (root) CMD (run-parts /etc/cron.hourly)\n",
+    ];
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/chunkmap_decode");
+    for file in fs::read_dir(dir).unwrap() {
+        let path = file.unwrap().path();
+        let data = fs::read(&path).unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let mut encoder = ChunkMap::new(0.5);
+        let mut decoder = ChunkMap::new(0.5);
+        let mut buf = Vec::new();
+        for sample in samples.iter() {
+            buf.clear();
+            encoder.encode(sample, &mut buf).unwrap();
+            decoder.decode(&buf, &mut Vec::new()).unwrap();
+        }
+        // of the hand written seeds, valid_* are well formed frames and the
+        // rest must be rejected; inputs saved by the fuzzer are named by hash
+        let result = decoder.decode(&data, &mut Vec::new());
+        if name.contains('_') {
+            assert_eq!(result.is_ok(), name.starts_with("valid_"), "{}", name);
+        }
+        if result.is_ok() {
+            continue;
+        }
+
+        // a rejected frame must not have moved the decoder away from the encoder
+        buf.clear();
+        encoder.encode(samples[1], &mut buf).unwrap();
+        let mut out = Vec::new();
+        decoder.decode(&buf, &mut out).unwrap();
+        assert_eq!(&out[..], samples[1]);
+    }
+}
+
+#[test]
+pub fn fingerprint_test() {
+    let lines = [&b"GET /index.html 200\n"[..], b"GET /index.html 304\n", b"POST /login 403\n"];
+    let mut encoder = ChunkMap::new(0.5);
+    let mut decoder = ChunkMap::new(0.5);
+    // frames say what to insert, so the decoder's own threshold does not matter
+    let mut skewed = ChunkMap::new(0.1);
+    for line in lines.iter() {
+        let mut buf = Vec::new();
+        encoder.encode(line, &mut buf).unwrap();
+        decoder.decode(&buf, &mut Vec::new()).unwrap();
+        skewed.decode(&buf, &mut Vec::new()).unwrap();
+        assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+        assert_eq!(encoder.fingerprint(), skewed.fingerprint());
+    }
+    skewed.insert(lines[0].to_vec(), true);
+    assert!(encoder.fingerprint() != skewed.fingerprint());
+}
+
+#[test]
+pub fn state_test() {
+    let lines = [
+        &b"Jan 12 10:00:01 host sshd[100]: Accepted publickey for root\n"[..],
+        b"Jan 12 10:00:02 host kernel: eth0 link up\n",
+        b"Jan 12 10:00:03 host cron[7]: (root) CMD (run-parts /etc/cron.hourly)\n",
+        b"Jan 12 10:00:04 host sshd[102]: Accepted publickey for admin\n",
+    ];
+    let mut encoder = ChunkMap::new(0.5);
+    let mut decoder = ChunkMap::new(0.5);
+    for line in lines[..3].iter() {
+        let mut buf = Vec::new();
+        encoder.encode(line, &mut buf).unwrap();
+        decoder.decode(&buf, &mut Vec::new()).unwrap();
+    }
+    // leave a hole in the slab so the free list has to survive the round trip
+    encoder.remove(1);
+    decoder.remove(1);
+
+    let (mut enc_state, mut dec_state) = (Vec::new(), Vec::new());
+    encoder.save_state(&mut enc_state).unwrap();
+    decoder.save_state(&mut dec_state).unwrap();
+    let mut restored_encoder = ChunkMap::new(0.0);
+    let mut restored_decoder = ChunkMap::new(0.0);
+    restored_encoder.load_state(&mut &enc_state[..]).unwrap();
+    restored_decoder.load_state(&mut &dec_state[..]).unwrap();
+    assert_eq!(restored_encoder.fingerprint(), encoder.fingerprint());
+    assert!(restored_decoder.load_state(&mut &dec_state[..10]).is_err());
+
+    for line in lines.iter() {
+        let mut buf = Vec::new();
+        restored_encoder.encode(line, &mut buf).unwrap();
+        let mut out = Vec::new();
+        restored_decoder.decode(&buf, &mut out).unwrap();
+        decoder.decode(&buf, &mut Vec::new()).unwrap();
+        assert_eq!(&out[..], *line);
+    }
+    assert_eq!(restored_decoder.fingerprint(), restored_encoder.fingerprint());
+    assert_eq!(decoder.fingerprint(), restored_encoder.fingerprint());
+
+    // with a memory budget, restarting one side only must not change what
+    // it evicts
+    let corpus = include_bytes!("../corpus/logs.txt");
+    let builder = || ChunkMap::builder().main_size(1 << 20).memory_budget(16 * 1024);
+    for &restart_encoder in [true, false].iter() {
+        let mut encoder = builder().build();
+        let mut decoder = builder().build();
+        for (i, line) in corpus.split_inclusive(|&b| b == b'\n').enumerate() {
+            if i == 84 {
+                let side = if restart_encoder { &mut encoder } else { &mut decoder };
+                let mut state = Vec::new();
+                side.save_state(&mut state).unwrap();
+                *side = ChunkMap::new(0.5);
+                side.load_state(&mut &state[..]).unwrap();
+            }
+            let mut buf = Vec::new();
+            encoder.encode(line, &mut buf).unwrap();
+            let mut out = Vec::new();
+            decoder.decode(&buf, &mut out).unwrap();
+            assert_eq!(&out[..], line);
+            assert_eq!(encoder.memory_usage(), decoder.memory_usage(), "record {}", i);
+            assert_eq!(encoder.fingerprint(), decoder.fingerprint(), "record {}", i);
+        }
+    }
+}
+
+#[test]
+pub fn chunk_size_test() {
+    let corpus = include_bytes!("../corpus/logs.txt");
+    for &chunk_size in [3, DEFAULT_CHUNK_SIZE, 8, 12].iter() {
+        let mut encoder = ChunkMap::builder().chunk_size(chunk_size).build();
+        let mut decoder = ChunkMap::builder().chunk_size(chunk_size).build();
+        let mut compressed = 0;
+        for line in corpus.split_inclusive(|&b| b == b'\n') {
+            let mut buf = Vec::new();
+            compressed += encoder.encode(line, &mut buf).unwrap();
+            let mut out = Vec::new();
+            decoder.decode(&buf, &mut out).unwrap();
+            assert_eq!(&out[..], line);
+        }
+        assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+        assert!(compressed < corpus.len() / 2, "chunk size {}", chunk_size);
+    }
+}
+
+#[test]
+pub fn eden_test() {
+    let lines = [
+        &b"alpha one two three four\n"[..],
+        b"bravo five six seven eight\n",
+        b"alpha one two three four!\n",
+        b"charlie nine ten eleven\n",
+        b"delta twelve thirteen\n",
+    ];
+    let mut encoder = ChunkMap::builder().eden_size(2).main_size(1).build();
+    let mut decoder = ChunkMap::builder().eden_size(2).main_size(1).build();
+    for line in lines.iter() {
+        let mut buf = Vec::new();
+        encoder.encode(line, &mut buf).unwrap();
+        let mut out = Vec::new();
+        decoder.decode(&buf, &mut out).unwrap();
+        assert_eq!(&out[..], *line);
+    }
+    // alpha was hit and promoted, bravo never was and aged out of the eden
+    assert!(!encoder.entries[0].eden);
+    assert_eq!(&encoder.entries[0].data[..], lines[0]);
+    assert_eq!(encoder.eden, [2, 1]);
+    assert_eq!(&encoder.entries[1].data[..], lines[4]);
+    assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+}
+
+#[test]
+pub fn policy_test() {
+    let corpus = include_bytes!("../corpus/logs.txt");
+    for &policy in [Policy::Lru, Policy::LfuAging, Policy::Arc, Policy::Fifo].iter() {
+        let builder = || ChunkMap::builder().eden_size(4).main_size(16).policy(policy);
+        let mut encoder = builder().build();
+        let mut decoder = builder().build();
+        for (i, line) in corpus.split_inclusive(|&b| b == b'\n').enumerate() {
+            let mut buf = Vec::new();
+            encoder.encode(line, &mut buf).unwrap();
+            let mut out = Vec::new();
+            decoder.decode(&buf, &mut out).unwrap();
+            assert_eq!(&out[..], line);
+            if i == 400 {
+                // swap in a decoder restored half way through
+                let mut state = Vec::new();
+                decoder.save_state(&mut state).unwrap();
+                decoder = ChunkMap::new(0.0);
+                decoder.load_state(&mut &state[..]).unwrap();
+            }
+        }
+        assert_eq!(encoder.policy.len(), 16, "{}", policy.name());
+        assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+    }
+}
+
+#[test]
+pub fn memory_budget_test() {
+    let corpus = include_bytes!("../corpus/logs.txt");
+    let budget = 16 * 1024;
+    let builder = || ChunkMap::builder().main_size(1 << 20).memory_budget(budget);
+    let mut encoder = builder().build();
+    let mut decoder = builder().build();
+    let mut peak = 0;
+    for line in corpus.split_inclusive(|&b| b == b'\n') {
+        let mut buf = Vec::new();
+        encoder.encode(line, &mut buf).unwrap();
+        let mut out = Vec::new();
+        decoder.decode(&buf, &mut out).unwrap();
+        assert_eq!(&out[..], line);
+        assert!(encoder.memory_usage() <= budget);
+        peak = peak.max(encoder.memory_usage());
+    }
+    assert!(peak > budget / 2);
+    // evicted lines take their keys with them
+    assert!(encoder.map.values().all(|matches| matches.stale < matches.list.len()));
+    let lines: usize = encoder.entries.iter().map(|(_, entry)| encoder.cost(entry.data.len())).sum();
+    assert_eq!(encoder.memory_usage(), lines + encoder.map.len() * KEY_OVERHEAD);
+    assert_eq!(encoder.memory_usage(), decoder.memory_usage());
+    assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+}
+
+#[test]
+pub fn local_test() {
+    let record = b"{\"user\":\"alice\",\"role\":\"admin\",\"user\":\"bob\",\"role\":\"admin\"}                \n";
+    let mut encoder = ChunkMap::new(0.5);
+    let mut decoder = ChunkMap::new(0.5);
+    let mut buf = Vec::new();
+    encoder.encode(record, &mut buf).unwrap();
+    assert!(buf.len() < record.len() - 16);
+    let mut out = Vec::new();
+    decoder.decode(&buf, &mut out).unwrap();
+    assert_eq!(&out[..], &record[..]);
+
+    // reaching before the record, a zero distance and an over long copy
+    let bad_frames = [
+        &[0u8, 2, b'a', b'b', 1, 4, 3][..],
+        &[0, 2, b'a', b'b', 1, 4, 0],
+        &[0, 1, b'a', 1, 0x81, 0x80, 0x04, 1],
+    ];
+    for bad in bad_frames.iter() {
+        assert!(decoder.decode(bad, &mut Vec::new()).is_err());
+    }
+    assert!(decoder.decode(&[0, 1, b'a', 1, 7, 1], &mut out).is_ok());
+    assert!(out.ends_with(b"aaaaaaaa"));
+}
+
+#[test]
+pub fn level_test() {
+    let corpus = include_bytes!("../corpus/logs.txt");
+    // at the default threshold the optimal parser wins little, inserting every
+    // record gives both parsers much the same history and it wins far more
+    for &(threshold, gain) in [(0.5, 100), (0.0, 10)].iter() {
+        let mut sizes = Vec::new();
+        for &level in [LEVEL_GREEDY, LEVEL_OPTIMAL].iter() {
+            let mut encoder = ChunkMap::builder().level(level).insert_threshold(threshold).build();
+            let mut decoder = ChunkMap::new(threshold);
+            let mut compressed = 0;
+            for line in corpus.split_inclusive(|&b| b == b'\n') {
+                let mut buf = Vec::new();
+                compressed += encoder.encode(line, &mut buf).unwrap();
+                let mut out = Vec::new();
+                decoder.decode(&buf, &mut out).unwrap();
+                assert_eq!(&out[..], line);
+            }
+            assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+            sizes.push(compressed);
+        }
+        assert!(
+            sizes[1] < sizes[0] - sizes[0] / gain,
+            "threshold {}: greedy {} optimal {} of {}",
+            threshold,
+            sizes[0],
+            sizes[1],
+            corpus.len()
+        );
+    }
+}
+
+#[test]
+pub fn min_match_test() {
+    let line = b"wxyz0123456789abcdefghij\n";
+    for &(record, min_match, literal) in [
+        // a four byte reference between literals costs more than it saves
+        (&b"(-_-wxyz!?#;"[..], 0, true),
+        (b"(-_-0123456789!?#;", 0, false),
+        (b"(-_-0123456789!?#;", 12, true),
+    ].iter()
+    {
+        let mut encoder = ChunkMap::builder().eden_size(0).min_match(min_match).build();
+        encoder.insert(line.to_vec(), false);
+        let mut buf = Vec::new();
+        encoder.encode(record, &mut buf).unwrap();
+        assert_eq!(split_insert(buf[0] as u64).0 == 0 && buf[1] as usize == record.len(), literal);
+    }
+}
+
+/// The streams under corpus/reference were written on a little endian host;
+/// every target has to produce and read back exactly the same bytes.
+#[test]
+pub fn reference_test() {
+    assert_eq!(chunk_key(b"ab"), 0x6162);
+    assert_eq!(chunk_key(b"GET "), 0x4745_5420);
+    assert_eq!(chunk_key(b"01234567"), 0x3031_3233_3435_3637);
+    assert_eq!(chunk_key(b"Accepted pub"), 0xb21d_bf97_4879_4387);
+
+    let lines = [
+        &b"Jan 12 10:00:01 host sshd[100]: Accepted publickey for root\n"[..],
+        b"Jan 12 10:00:02 host sshd[101]: Accepted publickey for admin\n",
+        b"Jan 12 10:00:03 host sshd[102]: Accepted publickey for root\n",
+    ];
+    let references = [
+        (4, &include_bytes!("../corpus/reference/chunkmap_4.bin")[..], 0x0f72_7d92_1463_46a5),
+        (12, &include_bytes!("../corpus/reference/chunkmap_12.bin")[..], 0x0c63_92a7_138b_e7df),
+    ];
+    for &(chunk_size, stream, fingerprint) in references.iter() {
+        let mut encoder = ChunkMap::builder().chunk_size(chunk_size).build();
+        let mut decoder = ChunkMap::builder().chunk_size(chunk_size).build();
+        let mut pos = 0;
+        for line in lines.iter() {
+            let mut buf = Vec::new();
+            encoder.encode(line, &mut buf).unwrap();
+            let len = next_uvarint(stream, &mut pos).unwrap() as usize;
+            let frame = &stream[pos..pos + len];
+            pos += len;
+            assert_eq!(&buf[..], frame);
+            let mut out = Vec::new();
+            decoder.decode(frame, &mut out).unwrap();
+            assert_eq!(&out[..], *line);
+        }
+        assert_eq!(pos, stream.len());
+        assert_eq!(encoder.fingerprint(), fingerprint);
+        assert_eq!(decoder.fingerprint(), fingerprint);
+    }
+}
+
+#[test]
+pub fn command_test() {
+    fn roundtrip(encoder: &mut ChunkMap, decoder: &mut ChunkMap, record: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encoder.encode(record, &mut buf).unwrap();
+        let mut out = Vec::new();
+        decoder.decode(&buf, &mut out).unwrap();
+        assert_eq!(&out[..], record);
+        assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+        buf
+    }
+    let template = b"Jan 12 10:00:01 host sshd[100]: Accepted publickey for root\n";
+    let build = || ChunkMap::builder().eden_size(0).main_size(2).insert_threshold(0.0).build();
+    let (mut encoder, mut decoder) = (build(), build());
+    roundtrip(&mut encoder, &mut decoder, template);
+
+    // a pinned line outlives any number of records that would have pushed it out
+    encoder.queue(Command::Pin(0)).unwrap();
+    roundtrip(&mut encoder, &mut decoder, b"GET /index.html 200\n");
+    for i in 0..10 {
+        let line = format!("line {} with nothing in common {}\n", i, i * 7919);
+        roundtrip(&mut encoder, &mut decoder, line.as_bytes());
+    }
+    assert_eq!(roundtrip(&mut encoder, &mut decoder, template), [5, 60, 0]);
+
+    // and so does a restart
+    let mut state = Vec::new();
+    decoder.save_state(&mut state).unwrap();
+    let mut decoder = ChunkMap::new(0.5);
+    decoder.load_state(&mut &state[..]).unwrap();
+    assert_eq!(decoder.pinned, 1);
+
+    assert!(encoder.queue(Command::Evict(40)).is_err());
+    encoder.queue(Command::Evict(0)).unwrap();
+    assert!(encoder.queue(Command::Pin(0)).is_err());
+    encoder.queue(Command::Reset).unwrap();
+    encoder.queue(Command::NoInsert).unwrap();
+    // queued commands wait for the next record, through a restart too
+    assert!(!encoder.entries.is_empty());
+    let mut state = Vec::new();
+    encoder.save_state(&mut state).unwrap();
+    let mut encoder = ChunkMap::new(0.5);
+    encoder.load_state(&mut &state[..]).unwrap();
+    assert_eq!(encoder.commands.len(), 3);
+    roundtrip(&mut encoder, &mut decoder, template);
+    assert_eq!(encoder.entries.len(), 0);
+    assert_eq!(decoder.entries.len(), 0);
+
+    // unknown opcodes and commands on empty slots leave the decoder as it was
+    let fingerprint = decoder.fingerprint();
+    let bad_frames = [
+        &[0u8, 0, 9][..],
+        &[0, 0, OP_EVICT as u8, 5],
+        &[0, 0, OP_RESET as u8, 0, 0, OP_PIN as u8, 0],
+    ];
+    for bad in bad_frames.iter() {
+        assert!(decoder.decode(bad, &mut Vec::new()).is_err());
+        assert_eq!(decoder.fingerprint(), fingerprint);
+    }
 }
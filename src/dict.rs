@@ -0,0 +1,221 @@
+extern crate byteorder;
+extern crate fnv;
+extern crate zstd;
+
+use self::byteorder::{ReadBytesExt, WriteBytesExt};
+use self::fnv::{FnvHashMap, FnvHashSet};
+
+use super::checksum::Crc32;
+use super::chunkmap::DEFAULT_CHUNK_SIZE;
+use super::state::{read_bytes, write_bytes};
+use super::varint::{read_uvarint, write_uvarint};
+use super::DsscError;
+
+use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+use std::io::{Read, Write};
+
+pub const DICT_MAGIC: [u8; 4] = *b"DSCD";
+pub const DICT_VERSION: u8 = 1;
+
+/// Sample records shared by both ends of a stream, used to warm up the history
+/// cache before the first real record.
+///
+/// A dictionary file is either plain text, one sample per line, or the binary
+/// form written by `write`:
+///
+/// ```text
+/// magic    4 bytes "DSCD"
+/// version  u8
+/// records  uvarint count, then uvarint(len) + bytes for each
+/// zstd     u8 1 followed by uvarint(len) + trained zstd dictionary, or u8 0
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Dictionary {
+    pub records: Vec<Vec<u8>>,
+    /// A trained zstd dictionary, used by `ZstdBlock` in place of the records.
+    pub zstd: Option<Vec<u8>>,
+}
+
+impl Dictionary {
+    /// Splits `buf` into lines, keeping the line endings just like linedssc does.
+    pub fn from_lines(buf: &[u8]) -> Dictionary {
+        let records = buf.split_inclusive(|&b| b == b'\n')
+            .map(|line| line.to_vec())
+            .collect();
+        Dictionary {
+            records,
+            zstd: None,
+        }
+    }
+
+    /// Builds a dictionary from a sample of records: up to `max_records` of the
+    /// samples that best cover the chunks seen across the sample, and a zstd
+    /// dictionary of at most `zstd_size` bytes unless that is 0.
+    pub fn train(
+        samples: &[Vec<u8>],
+        max_records: usize,
+        zstd_size: usize,
+    ) -> Result<Dictionary, DsscError> {
+        let zstd = if zstd_size != 0 {
+            let trained = zstd::dict::from_samples(samples, zstd_size)
+                .map_err(|err| DsscError::Backend(err.to_string()))?;
+            Some(trained)
+        } else {
+            None
+        };
+        Ok(Dictionary {
+            records: representative_records(samples, max_records),
+            zstd,
+        })
+    }
+
+    /// Accepts either form of dictionary file.
+    pub fn load(buf: &[u8]) -> Result<Dictionary, DsscError> {
+        if buf.starts_with(&DICT_MAGIC) {
+            Dictionary::read(&mut &buf[..])
+        } else {
+            Ok(Dictionary::from_lines(buf))
+        }
+    }
+
+    pub fn read(r: &mut dyn Read) -> Result<Dictionary, DsscError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != DICT_MAGIC {
+            return Err(DsscError::BadMagic);
+        }
+        let version = r.read_u8()?;
+        if version != DICT_VERSION {
+            return Err(DsscError::UnsupportedVersion { version });
+        }
+        let mut records = Vec::new();
+        for _ in 0..read_uvarint(r)? {
+            records.push(read_bytes(r)?);
+        }
+        let zstd = match r.read_u8()? {
+            0 => None,
+            1 => Some(read_bytes(r)?),
+            _ => return Err(DsscError::CorruptState("bad zstd dictionary tag")),
+        };
+        Ok(Dictionary { records, zstd })
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        w.write_all(&DICT_MAGIC)?;
+        w.write_u8(DICT_VERSION)?;
+        write_uvarint(w, self.records.len() as u64)?;
+        for record in &self.records {
+            write_bytes(w, record)?;
+        }
+        match self.zstd {
+            Some(ref zstd) => {
+                w.write_u8(1)?;
+                write_bytes(w, zstd)?;
+            }
+            None => w.write_u8(0)?,
+        }
+        Ok(())
+    }
+
+    /// Identifies the dictionary in stream headers, never 0.
+    pub fn id(&self) -> u32 {
+        let mut crc = Crc32::new();
+        for record in &self.records {
+            crc.update(&(record.len() as u64).to_le_bytes());
+            crc.update(record);
+        }
+        if let Some(ref zstd) = self.zstd {
+            crc.update(zstd);
+        }
+        crc.finish().max(1)
+    }
+}
+
+/// Greedy set cover over chunks: every pick is the sample whose not yet covered
+/// chunks occur in the most samples. Scores only ever drop, so a stale score
+/// at the top of the heap is rescored and pushed back instead of rescanning
+/// every sample per pick.
+fn representative_records(samples: &[Vec<u8>], max_records: usize) -> Vec<Vec<u8>> {
+    let mut seen = FnvHashSet::default();
+    let chunks: Vec<FnvHashSet<&[u8]>> = samples
+        .iter()
+        .map(|s| {
+            if seen.insert(&s[..]) {
+                s.windows(DEFAULT_CHUNK_SIZE).collect()
+            } else {
+                FnvHashSet::default()
+            }
+        })
+        .collect();
+    let mut frequency: FnvHashMap<&[u8], usize> = FnvHashMap::default();
+    for set in &chunks {
+        for &c in set {
+            *frequency.entry(c).or_insert(0) += 1;
+        }
+    }
+
+    let mut covered = FnvHashSet::default();
+    let score = |set: &FnvHashSet<&[u8]>, covered: &FnvHashSet<&[u8]>| -> usize {
+        set.iter()
+            .filter(|c| !covered.contains(*c))
+            .map(|c| frequency[c])
+            .sum()
+    };
+    let mut heap: BinaryHeap<(usize, Reverse<usize>)> = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, set)| (score(set, &covered), Reverse(i)))
+        .filter(|&(score, _)| score != 0)
+        .collect();
+
+    let mut records = Vec::new();
+    while records.len() < max_records {
+        let (old_score, Reverse(i)) = match heap.pop() {
+            Some(top) => top,
+            None => break,
+        };
+        let new_score = score(&chunks[i], &covered);
+        if new_score == 0 {
+            continue;
+        }
+        if new_score < old_score {
+            heap.push((new_score, Reverse(i)));
+            continue;
+        }
+        covered.extend(chunks[i].iter().cloned());
+        records.push(samples[i].clone());
+    }
+    records
+}
+
+#[test]
+pub fn dict_test() {
+    let mut dict = Dictionary::from_lines(b"GET / 200\nGET /favicon.ico 404\nno newline");
+    dict.zstd = Some(b"raw content".to_vec());
+    assert_eq!(dict.records.len(), 3);
+    assert_eq!(&dict.records[0][..], b"GET / 200\n");
+
+    let mut buf = Vec::new();
+    dict.write(&mut buf).unwrap();
+    let loaded = Dictionary::load(&buf).unwrap();
+    assert_eq!(loaded, dict);
+    assert_eq!(loaded.id(), dict.id());
+    assert!(Dictionary::from_lines(b"GET / 200\n").id() != dict.id());
+}
+
+#[test]
+pub fn train_test() {
+    let samples: Vec<Vec<u8>> = [
+        &b"GET /index.html HTTP/1.1 200\n"[..],
+        b"GET /index.html HTTP/1.1 304\n",
+        b"GET /index.html HTTP/1.1 200\n",
+        b"POST /login HTTP/1.1 403\n",
+        b"ok\n",
+    ].iter()
+        .map(|s| s.to_vec())
+        .collect();
+    let dict = Dictionary::train(&samples, 2, 0).unwrap();
+    assert_eq!(dict.records, vec![samples[0].clone(), samples[3].clone()]);
+    assert_eq!(Dictionary::train(&samples, 10, 0).unwrap().records.len(), 3);
+}
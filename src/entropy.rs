@@ -0,0 +1,423 @@
+use super::dict::Dictionary;
+use super::state::{read_preamble, write_preamble, KIND_HUFFMAN};
+use super::varint::{next_uvarint, put_uvarint, read_uvarint, write_uvarint};
+use super::{Compressor, DsscError};
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{Read, Write};
+
+// An entropy coding stage that wraps any compressor. Its output is split into
+// fields by the compressor's `Fields` parser, and every field is coded with its
+// own adaptive Huffman model, so literals never skew the statistics of the
+// reference fields and the other way around. The models carry over from record
+// to record; both ends rebuild their codes at the same record boundaries.
+
+pub const MAX_CODE_LEN: usize = 15;
+/// Counts are halved once a model has seen this many symbols, so old records
+/// fade out.
+const MAX_TOTAL: u32 = 1 << 16;
+const FIRST_REBUILD: u32 = 32;
+const MAX_REBUILD: u32 = 4096;
+
+/// Tells which field of a compressed record each byte belongs to, from the
+/// bytes before it alone, so a decoder can follow along while it decodes.
+pub trait Fields: Send {
+    /// Number of fields, each gets its own model.
+    fn count(&self) -> usize;
+    /// The field of the next byte.
+    fn field(&self) -> usize;
+    fn next(&mut self, byte: u8);
+    /// Back to the start of a record.
+    fn reset(&mut self);
+}
+
+/// A single field for compressors whose output has no known structure.
+pub struct Bytes;
+
+impl Fields for Bytes {
+    fn count(&self) -> usize {
+        1
+    }
+    fn field(&self) -> usize {
+        0
+    }
+    fn next(&mut self, _byte: u8) {}
+    fn reset(&mut self) {}
+}
+
+/// Canonical Huffman code over bytes, built from counts that every record adds to.
+struct Model {
+    counts: [u32; 256],
+    total: u32,
+    pending: u32,
+    rebuild: u32,
+    lens: [u8; 256],
+    codes: [u16; 256],
+    len_counts: [u16; MAX_CODE_LEN + 1],
+    symbols: [u8; 256],
+}
+
+impl Model {
+    fn new() -> Self {
+        let mut model = Model {
+            counts: [1; 256],
+            total: 256,
+            pending: 0,
+            rebuild: FIRST_REBUILD,
+            lens: [8; 256],
+            codes: [0; 256],
+            len_counts: [0; MAX_CODE_LEN + 1],
+            symbols: [0; 256],
+        };
+        model.assign_codes();
+        model
+    }
+
+    fn add(&mut self, byte: u8) {
+        self.counts[byte as usize] += 1;
+        self.total += 1;
+        self.pending += 1;
+        if self.total > MAX_TOTAL {
+            self.total = 0;
+            for count in self.counts.iter_mut() {
+                *count = (*count).div_ceil(2);
+                self.total += *count;
+            }
+        }
+    }
+
+    /// Called once per record, rebuilds the code every `rebuild` symbols and
+    /// doubles the interval up to `MAX_REBUILD` while the model settles.
+    fn end_record(&mut self) {
+        if self.pending < self.rebuild {
+            return;
+        }
+        self.pending = 0;
+        self.rebuild = (self.rebuild * 2).min(MAX_REBUILD);
+        self.lens = code_lengths(&self.counts);
+        self.assign_codes();
+    }
+
+    fn assign_codes(&mut self) {
+        self.len_counts = [0; MAX_CODE_LEN + 1];
+        for &len in self.lens.iter() {
+            self.len_counts[len as usize] += 1;
+        }
+        let mut next = [0u32; MAX_CODE_LEN + 2];
+        let mut offsets = [0usize; MAX_CODE_LEN + 2];
+        for len in 1..=MAX_CODE_LEN {
+            next[len + 1] = (next[len] + self.len_counts[len] as u32) << 1;
+            offsets[len + 1] = offsets[len] + self.len_counts[len] as usize;
+        }
+        for symbol in 0..256 {
+            let len = self.lens[symbol] as usize;
+            self.codes[symbol] = next[len] as u16;
+            next[len] += 1;
+            self.symbols[offsets[len]] = symbol as u8;
+            offsets[len] += 1;
+        }
+    }
+
+    fn encode(&self, byte: u8, bits: &mut BitWriter) {
+        bits.write(self.codes[byte as usize] as u32, self.lens[byte as usize] as u32);
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u8, DsscError> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for len in 1..=MAX_CODE_LEN {
+            code |= bits.read()?;
+            let count = self.len_counts[len] as u32;
+            if code < first + count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        // unreachable with a complete code
+        Err(DsscError::CorruptBlock { offset: bits.pos / 8 })
+    }
+
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        for &count in self.counts.iter() {
+            write_uvarint(w, count as u64)?;
+        }
+        write_uvarint(w, self.pending as u64)?;
+        write_uvarint(w, self.rebuild as u64)?;
+        w.write_all(&self.lens)?;
+        Ok(())
+    }
+
+    fn load_state(r: &mut dyn Read) -> Result<Model, DsscError> {
+        let mut model = Model::new();
+        model.total = 0;
+        for count in model.counts.iter_mut() {
+            *count = read_uvarint(r)? as u32;
+            if *count == 0 || *count > MAX_TOTAL {
+                return Err(DsscError::CorruptState("symbol count out of range"));
+            }
+            model.total += *count;
+        }
+        if model.total > MAX_TOTAL {
+            return Err(DsscError::CorruptState("symbol counts out of range"));
+        }
+        let pending = read_uvarint(r)?;
+        let rebuild = read_uvarint(r)?;
+        if rebuild < FIRST_REBUILD as u64 || rebuild > MAX_REBUILD as u64 || pending > rebuild {
+            return Err(DsscError::CorruptState("rebuild interval out of range"));
+        }
+        model.pending = pending as u32;
+        model.rebuild = rebuild as u32;
+        r.read_exact(&mut model.lens)?;
+        // every code word has to be there exactly once
+        let mut kraft = 0;
+        for &len in model.lens.iter() {
+            if len == 0 || len as usize > MAX_CODE_LEN {
+                return Err(DsscError::CorruptState("code length out of range"));
+            }
+            kraft += 1 << (MAX_CODE_LEN - len as usize);
+        }
+        if kraft != 1 << MAX_CODE_LEN {
+            return Err(DsscError::CorruptState("huffman code is not complete"));
+        }
+        model.assign_codes();
+        Ok(model)
+    }
+}
+
+/// Huffman code lengths for `counts`, flattened until none is longer than
+/// `MAX_CODE_LEN`.
+fn code_lengths(counts: &[u32; 256]) -> [u8; 256] {
+    let mut weights: Vec<u64> = counts.iter().map(|&c| c.max(1) as u64).collect();
+    loop {
+        let mut heap: BinaryHeap<Reverse<(u64, usize)>> =
+            weights.iter().enumerate().map(|(i, &w)| Reverse((w, i))).collect();
+        let mut parent = vec![0; 2 * 256 - 1];
+        let mut next = 256;
+        while heap.len() > 1 {
+            let Reverse((wa, a)) = heap.pop().unwrap();
+            let Reverse((wb, b)) = heap.pop().unwrap();
+            parent[a] = next;
+            parent[b] = next;
+            heap.push(Reverse((wa + wb, next)));
+            next += 1;
+        }
+        // parents always come after their children
+        let mut depth = vec![0usize; next];
+        for node in (0..next - 1).rev() {
+            depth[node] = depth[parent[node]] + 1;
+        }
+        if depth[..256].iter().all(|&d| d <= MAX_CODE_LEN) {
+            let mut lens = [0; 256];
+            for (len, &d) in lens.iter_mut().zip(depth.iter()) {
+                *len = d as u8;
+            }
+            return lens;
+        }
+        for weight in weights.iter_mut() {
+            *weight = (*weight >> 1).max(1);
+        }
+    }
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        BitWriter { out, acc: 0, bits: 0 }
+    }
+
+    /// Most significant bit first.
+    fn write(&mut self, code: u32, len: u32) {
+        self.acc = (self.acc << len) | code;
+        self.bits += len;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.out.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    fn finish(self) {
+        if self.bits != 0 {
+            self.out.push((self.acc << (8 - self.bits)) as u8);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self) -> Result<u32, DsscError> {
+        let byte = self.buf.get(self.pos / 8).ok_or(DsscError::TruncatedBlock {
+            offset: self.pos / 8,
+            len: 1,
+        })?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit as u32)
+    }
+}
+
+/// Entropy codes the output of another compressor. Every record becomes
+/// `uvarint(len of the inner record)` followed by the Huffman coded bytes.
+pub struct Huffman {
+    inner: Box<dyn Compressor>,
+    fields: Box<dyn Fields>,
+    models: Vec<Model>,
+    scratch: Vec<u8>,
+}
+
+impl Huffman {
+    pub fn new(inner: Box<dyn Compressor>) -> Self {
+        let fields = inner.fields();
+        let models = (0..fields.count()).map(|_| Model::new()).collect();
+        Huffman {
+            inner,
+            fields,
+            models,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Adds the record in `scratch` to the models, the same way on both ends.
+    fn learn(&mut self) {
+        self.fields.reset();
+        for &byte in self.scratch.iter() {
+            self.models[self.fields.field()].add(byte);
+            self.fields.next(byte);
+        }
+        for model in self.models.iter_mut() {
+            model.end_record();
+        }
+    }
+}
+
+impl Compressor for Huffman {
+    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
+        let old_buf_len = out_buf.len();
+        self.scratch.clear();
+        self.inner.encode(in_buf, &mut self.scratch)?;
+        let mut varint_buf = [0; 10];
+        let varint_len = put_uvarint(&mut varint_buf, self.scratch.len() as u64);
+        out_buf.extend_from_slice(&varint_buf[0..varint_len]);
+        let mut bits = BitWriter::new(out_buf);
+        self.fields.reset();
+        for &byte in self.scratch.iter() {
+            self.models[self.fields.field()].encode(byte, &mut bits);
+            self.fields.next(byte);
+        }
+        bits.finish();
+        self.learn();
+        Ok(out_buf.len() - old_buf_len)
+    }
+
+    /// The models only learn from records the inner compressor accepted, so a
+    /// corrupt record leaves both as they were.
+    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
+        let mut i = 0;
+        let len = next_uvarint(in_buf, &mut i)? as usize;
+        // every byte takes at least one bit
+        if len / 8 > in_buf.len() - i {
+            return Err(DsscError::TruncatedBlock { offset: i, len: len / 8 });
+        }
+        let mut bits = BitReader { buf: &in_buf[i..], pos: 0 };
+        self.scratch.clear();
+        self.fields.reset();
+        for _ in 0..len {
+            let byte = self.models[self.fields.field()].decode(&mut bits)?;
+            self.scratch.push(byte);
+            self.fields.next(byte);
+        }
+        let n = self.inner.decode(&self.scratch, out_buf)?;
+        self.learn();
+        Ok(n)
+    }
+
+    fn fingerprint(&self) -> u64 {
+        self.inner.fingerprint()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.inner.memory_usage()
+    }
+
+    fn prime(&mut self, dict: &Dictionary) -> Result<(), DsscError> {
+        self.inner.prime(dict)
+    }
+
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        write_preamble(w, KIND_HUFFMAN)?;
+        write_uvarint(w, self.models.len() as u64)?;
+        for model in self.models.iter() {
+            model.save_state(w)?;
+        }
+        self.inner.save_state(w)
+    }
+
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError> {
+        read_preamble(r, KIND_HUFFMAN)?;
+        if read_uvarint(r)? != self.fields.count() as u64 {
+            return Err(DsscError::CorruptState("wrong number of models"));
+        }
+        let mut models = Vec::new();
+        for _ in 0..self.fields.count() {
+            models.push(Model::load_state(r)?);
+        }
+        self.inner.load_state(r)?;
+        self.models = models;
+        Ok(())
+    }
+}
+
+#[test]
+pub fn huffman_test() {
+    use super::chunkmap::ChunkMap;
+    let corpus = include_bytes!("../corpus/logs.txt");
+    let mut plain = ChunkMap::new(0.5);
+    let mut encoder = Huffman::new(Box::new(ChunkMap::new(0.5)));
+    let mut decoder = Huffman::new(Box::new(ChunkMap::new(0.5)));
+    let (mut plain_len, mut coded_len) = (0, 0);
+    for (i, line) in corpus.split_inclusive(|&b| b == b'\n').enumerate() {
+        plain_len += plain.encode(line, &mut Vec::new()).unwrap();
+        let mut buf = Vec::new();
+        coded_len += encoder.encode(line, &mut buf).unwrap();
+        let mut out = Vec::new();
+        decoder.decode(&buf, &mut out).unwrap();
+        assert_eq!(&out[..], line);
+        if i == 400 {
+            let mut state = Vec::new();
+            decoder.save_state(&mut state).unwrap();
+            decoder = Huffman::new(Box::new(ChunkMap::new(0.0)));
+            decoder.load_state(&mut &state[..]).unwrap();
+        }
+    }
+    assert!(coded_len < plain_len * 9 / 10, "{} vs {}", coded_len, plain_len);
+    assert_eq!(encoder.fingerprint(), decoder.fingerprint());
+
+    // a frame that ends early is rejected and the models do not move
+    let mut buf = Vec::new();
+    encoder.encode(b"Jan 12 10:00:01 host kernel: eth0 link up\n", &mut buf).unwrap();
+    let truncated = &buf[..buf.len() / 2];
+    assert!(decoder.decode(truncated, &mut Vec::new()).is_err());
+    let mut out = Vec::new();
+    decoder.decode(&buf, &mut out).unwrap();
+    assert_eq!(&out[..], b"Jan 12 10:00:01 host kernel: eth0 link up\n");
+
+    // bits that match no code are a corrupt block, not a corrupt state
+    let mut model = Model::new();
+    model.len_counts = [0; MAX_CODE_LEN + 1];
+    let mut bits = BitReader { buf: &[0xff; 4], pos: 0 };
+    match model.decode(&mut bits) {
+        Err(DsscError::CorruptBlock { offset: 1 }) => {}
+        other => panic!("{:?}", other),
+    }
+}
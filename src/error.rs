@@ -0,0 +1,136 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum DsscError {
+    /// A varint ran off the end of the buffer.
+    TruncatedVarint { offset: usize },
+    /// A varint did not fit into 64 bits.
+    VarintOverflow { offset: usize },
+    /// A varint was padded with redundant zero groups.
+    OverlongVarint { offset: usize },
+    /// A block claims more bytes than are left in the buffer.
+    TruncatedBlock { offset: usize, len: usize },
+    /// A block holds bits that decode to no symbol.
+    CorruptBlock { offset: usize },
+    /// A block references a cache line beyond the cache capacity.
+    LineOutOfRange { line: usize },
+    /// A block references a cache slot that holds no line.
+    UnknownSlot { slot: usize },
+    /// A block references bytes past the end of a cache line.
+    OffsetOutOfRange { line: usize, offset: usize, len: usize },
+    /// A block continues from the previous line, but no block before it named one.
+    MissingLine { offset: usize },
+    /// A command block carries an opcode this build does not know.
+    UnknownCommand { op: u64 },
+    /// A record claims a decoded length its block does not carry, or one too
+    /// large to allocate.
+    BadRecordLength { len: u64 },
+    /// A back reference reaches before the start of the record or is too long.
+    BadBackReference { distance: usize, len: usize, produced: usize },
+    /// The stream does not start with the dssc magic bytes.
+    BadMagic,
+    UnsupportedVersion { version: u8 },
+    UnknownAlgorithm { id: u8 },
+    /// The stream was written with settings this build cannot decode.
+    Incompatible(String),
+    /// A record did not decode to the bytes it was encoded from.
+    ChecksumMismatch { record: u64 },
+    /// A record carried a checksum that does not match its contents.
+    BadChecksum,
+    /// The decoder cache no longer matches the encoder cache as of this record.
+    CacheDesync { record: u64 },
+    /// A record failed to decode.
+    BadRecord { record: u64, error: Box<DsscError> },
+    /// The stream as a whole did not match its trailer checksum.
+    StreamChecksumMismatch,
+    /// The stream ended before its trailer.
+    MissingTrailer,
+    /// A saved compressor state is inconsistent.
+    CorruptState(&'static str),
+    /// The compressor does not implement this operation.
+    Unsupported(&'static str),
+    /// The underlying compression library reported an error.
+    Backend(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for DsscError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DsscError::TruncatedVarint { offset } => {
+                write!(f, "truncated varint at byte {}", offset)
+            }
+            DsscError::VarintOverflow { offset } => write!(f, "varint overflow at byte {}", offset),
+            DsscError::OverlongVarint { offset } => write!(f, "overlong varint at byte {}", offset),
+            DsscError::TruncatedBlock { offset, len } => write!(
+                f,
+                "block at byte {} needs {} bytes past the end of input",
+                offset, len
+            ),
+            DsscError::CorruptBlock { offset } => {
+                write!(f, "corrupt block at offset {}", offset)
+            }
+            DsscError::LineOutOfRange { line } => {
+                write!(f, "cache line {} is out of range", line)
+            }
+            DsscError::UnknownSlot { slot } => write!(f, "cache slot {} is empty", slot),
+            DsscError::OffsetOutOfRange { line, offset, len } => write!(
+                f,
+                "reference {}..{} is past the end of cache line {}",
+                offset,
+                offset + len,
+                line
+            ),
+            DsscError::MissingLine { offset } => {
+                write!(f, "block at byte {} repeats a line before any was named", offset)
+            }
+            DsscError::UnknownCommand { op } => write!(f, "unknown cache command {}", op),
+            DsscError::BadRecordLength { len } => write!(f, "bad record length {}", len),
+            DsscError::BadBackReference { distance, len, produced } => write!(
+                f,
+                "back reference of {} bytes from {} back, {} decoded so far",
+                len, distance, produced
+            ),
+            DsscError::BadMagic => write!(f, "not a dssc stream"),
+            DsscError::UnsupportedVersion { version } => {
+                write!(f, "unsupported format version {}", version)
+            }
+            DsscError::UnknownAlgorithm { id } => write!(f, "unknown algorithm id {}", id),
+            DsscError::Incompatible(ref msg) => write!(f, "incompatible stream: {}", msg),
+            DsscError::ChecksumMismatch { record } => {
+                write!(f, "checksum mismatch in record {}", record)
+            }
+            DsscError::BadChecksum => write!(f, "record checksum mismatch"),
+            DsscError::CacheDesync { record } => {
+                write!(f, "decoder cache diverged from encoder at record {}", record)
+            }
+            DsscError::BadRecord { record, ref error } => {
+                write!(f, "record {}: {}", record, error)
+            }
+            DsscError::StreamChecksumMismatch => write!(f, "stream checksum mismatch"),
+            DsscError::MissingTrailer => write!(f, "stream ended without its trailer"),
+            DsscError::CorruptState(what) => write!(f, "corrupt state: {}", what),
+            DsscError::Unsupported(what) => write!(f, "{} is not supported", what),
+            DsscError::Backend(ref msg) => write!(f, "backend error: {}", msg),
+            DsscError::Io(ref err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl error::Error for DsscError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            DsscError::Io(ref err) => Some(err),
+            DsscError::BadRecord { ref error, .. } => Some(&**error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DsscError {
+    fn from(err: io::Error) -> Self {
+        DsscError::Io(err)
+    }
+}
@@ -0,0 +1,190 @@
+extern crate byteorder;
+
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::cache::Policy;
+use super::varint::{read_uvarint, write_uvarint};
+use super::DsscError;
+
+use std::io::{Read, Write};
+
+pub const MAGIC: [u8; 4] = *b"DSSC";
+pub const FORMAT_VERSION: u8 = 1;
+
+const FLAG_RECORD_CHECKSUM: u8 = 1;
+const FLAG_STREAM_CHECKSUM: u8 = 2;
+const FLAG_ENTROPY: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    ChunkMap,
+    Chunked,
+    Flate,
+    Zstd,
+}
+
+impl Algorithm {
+    pub fn id(self) -> u8 {
+        match self {
+            Algorithm::ChunkMap => 1,
+            Algorithm::Chunked => 2,
+            Algorithm::Flate => 3,
+            Algorithm::Zstd => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Algorithm> {
+        match id {
+            1 => Some(Algorithm::ChunkMap),
+            2 => Some(Algorithm::Chunked),
+            3 => Some(Algorithm::Flate),
+            4 => Some(Algorithm::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::ChunkMap => "chunkmap",
+            Algorithm::Chunked => "chunked",
+            Algorithm::Flate => "flate",
+            Algorithm::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "chunkmap" => Some(Algorithm::ChunkMap),
+            "chunked" => Some(Algorithm::Chunked),
+            "flate" => Some(Algorithm::Flate),
+            "zstd" => Some(Algorithm::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Leads every stream, so a decoder can set itself up the same way the encoder was.
+///
+/// ```text
+/// magic      4 bytes "DSSC"
+/// version    u8
+/// algorithm  u8
+/// flags      u8, 1 = crc32 after every record, 2 = crc32 trailer after the last record,
+///            4 = records are entropy coded by `entropy::Huffman`
+/// chunk size uvarint, 0 if the algorithm has no chunks
+/// cache size uvarint, 0 if the algorithm has no history cache
+/// eden size uvarint, lines new entries wait in before their first hit, 0 for none
+/// policy     u8, `Policy::id` of the cache eviction policy, 0 if there is no cache
+/// memory budget uvarint, bytes the cache may take on top of its line limits, 0 for none
+/// fingerprint interval uvarint, records between cache fingerprints, 0 for none
+/// dictionary id u32 little endian, `Dictionary::id` of the priming dictionary, 0 for none
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub algorithm: Algorithm,
+    pub record_checksum: bool,
+    pub stream_checksum: bool,
+    pub entropy: bool,
+    pub chunk_size: usize,
+    pub cache_size: usize,
+    pub eden_size: usize,
+    pub policy: Option<Policy>,
+    pub memory_budget: usize,
+    pub fingerprint_interval: usize,
+    pub dict_id: u32,
+}
+
+impl Header {
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        w.write_all(&MAGIC)?;
+        w.write_u8(FORMAT_VERSION)?;
+        w.write_u8(self.algorithm.id())?;
+        let mut flags = 0;
+        if self.record_checksum {
+            flags |= FLAG_RECORD_CHECKSUM;
+        }
+        if self.stream_checksum {
+            flags |= FLAG_STREAM_CHECKSUM;
+        }
+        if self.entropy {
+            flags |= FLAG_ENTROPY;
+        }
+        w.write_u8(flags)?;
+        write_uvarint(w, self.chunk_size as u64)?;
+        write_uvarint(w, self.cache_size as u64)?;
+        write_uvarint(w, self.eden_size as u64)?;
+        w.write_u8(self.policy.map(Policy::id).unwrap_or(0))?;
+        write_uvarint(w, self.memory_budget as u64)?;
+        write_uvarint(w, self.fingerprint_interval as u64)?;
+        w.write_u32::<LittleEndian>(self.dict_id)?;
+        Ok(())
+    }
+
+    pub fn read(r: &mut dyn Read) -> Result<Header, DsscError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(DsscError::BadMagic);
+        }
+        let version = r.read_u8()?;
+        if version != FORMAT_VERSION {
+            return Err(DsscError::UnsupportedVersion { version });
+        }
+        let id = r.read_u8()?;
+        let algorithm = Algorithm::from_id(id).ok_or(DsscError::UnknownAlgorithm { id })?;
+        let flags = r.read_u8()?;
+        if flags & !(FLAG_RECORD_CHECKSUM | FLAG_STREAM_CHECKSUM | FLAG_ENTROPY) != 0 {
+            return Err(DsscError::Incompatible(format!("unknown flags {:#x}", flags)));
+        }
+        let chunk_size = read_uvarint(r)? as usize;
+        let cache_size = read_uvarint(r)? as usize;
+        let eden_size = read_uvarint(r)? as usize;
+        let policy = match r.read_u8()? {
+            0 => None,
+            id => Some(Policy::from_id(id).ok_or_else(|| {
+                DsscError::Incompatible(format!("unknown cache policy {}", id))
+            })?),
+        };
+        let memory_budget = read_uvarint(r)? as usize;
+        let fingerprint_interval = read_uvarint(r)? as usize;
+        let dict_id = r.read_u32::<LittleEndian>()?;
+        Ok(Header {
+            algorithm,
+            record_checksum: flags & FLAG_RECORD_CHECKSUM != 0,
+            stream_checksum: flags & FLAG_STREAM_CHECKSUM != 0,
+            entropy: flags & FLAG_ENTROPY != 0,
+            chunk_size,
+            cache_size,
+            eden_size,
+            policy,
+            memory_budget,
+            fingerprint_interval,
+            dict_id,
+        })
+    }
+}
+
+#[test]
+pub fn header_test() {
+    let header = Header {
+        algorithm: Algorithm::ChunkMap,
+        record_checksum: true,
+        stream_checksum: true,
+        entropy: true,
+        chunk_size: 4,
+        cache_size: 245,
+        eden_size: 10,
+        policy: Some(Policy::Arc),
+        memory_budget: 1 << 20,
+        fingerprint_interval: 100,
+        dict_id: 0x1234_5678,
+    };
+    let mut buf = Vec::new();
+    header.write(&mut buf).unwrap();
+    assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+
+    buf[4] = FORMAT_VERSION + 1;
+    assert!(Header::read(&mut &buf[..]).is_err());
+    buf[0] = b'X';
+    assert!(Header::read(&mut &buf[..]).is_err());
+}
@@ -1,14 +1,62 @@
-#![feature(iterator_step_by)]
-#![feature(conservative_impl_trait)]
 pub mod chunked;
 pub mod other;
-mod cache;
+pub mod cache;
+pub mod checksum;
 pub mod chunkmap;
+pub mod dict;
+pub mod entropy;
+mod error;
+pub mod header;
+mod state;
 pub mod varint;
 
+pub use error::DsscError;
+
+use dict::Dictionary;
+use entropy::{Bytes, Fields};
+
+use std::io::{Read, Write};
+
+/// Both methods append to `out_buf` and return the number of bytes appended.
 pub trait Compressor: Send {
-    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>);
-    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>);
+    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError>;
+    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError>;
+
+    /// A cheap digest of the history cache. An encoder and a decoder that have
+    /// seen the same records report the same value; 0 if there is no cache.
+    fn fingerprint(&self) -> u64 {
+        0
+    }
+
+    /// Bytes the history cache holds as counted against a memory budget, 0 if
+    /// there is no cache.
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    /// Splits encoded records into fields for `entropy::Huffman`, which keeps
+    /// a separate model for each.
+    fn fields(&self) -> Box<dyn Fields> {
+        Box::new(Bytes)
+    }
+
+    /// Loads the dictionary samples into the history cache. Both ends of a
+    /// stream have to be primed with the same dictionary before the first record.
+    fn prime(&mut self, _dict: &Dictionary) -> Result<(), DsscError> {
+        Err(DsscError::Unsupported("priming with a dictionary"))
+    }
+
+    /// Writes a versioned snapshot of everything the next `encode` or `decode`
+    /// depends on. An encoder and a decoder restored from snapshots taken at the
+    /// same record carry on in lockstep.
+    fn save_state(&self, _w: &mut dyn Write) -> Result<(), DsscError> {
+        Err(DsscError::Unsupported("saving state"))
+    }
+
+    /// Replaces the current state with a snapshot written by `save_state`.
+    fn load_state(&mut self, _r: &mut dyn Read) -> Result<(), DsscError> {
+        Err(DsscError::Unsupported("loading state"))
+    }
 }
 
 /*
@@ -2,50 +2,289 @@ extern crate byteorder;
 extern crate clap;
 extern crate dssc;
 
-use dssc::Compressor;
-use dssc::chunked::ChunkedCompressor;
-use dssc::chunkmap::ChunkMap;
-use dssc::other::FlateStream;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use dssc::{Compressor, DsscError};
+use dssc::cache::{InsertPolicy, Policy};
+use dssc::checksum::{crc32, Crc32};
+use dssc::chunked::{self, ChunkedCompressor};
+use dssc::chunkmap::{self, ChunkMap};
+use dssc::dict::Dictionary;
+use dssc::entropy::Huffman;
+use dssc::header::{Algorithm, Header};
+use dssc::other::{FlateStream, ZstdBlock};
 use dssc::varint::{put_uvarint, read_uvarint};
-use std::ops::DerefMut;
-use std::io::{stdin, stdout, BufRead, BufReader, Error, Read, Write};
-use clap::{App, Arg};
+use std::io::{stdin, stdout, BufRead, BufReader, ErrorKind, Read, Write};
+use clap::{App, Arg, SubCommand};
 use std::fs::File;
+use std::process;
 
 const DEFAULT_THRESHOLD: f32 = 0.5;
+const DEFAULT_DICT_RECORDS: usize = 64;
+const DEFAULT_ZSTD_DICT_SIZE: usize = 16 * 1024;
 
-fn encode<R: Read, W: Write>(comp: &mut Compressor, input: R, mut output: W) -> Result<(), Error> {
+fn header_for(algorithm: Algorithm) -> Header {
+    let (chunk_size, cache_size, eden_size) = match algorithm {
+        Algorithm::ChunkMap => (
+            chunkmap::DEFAULT_CHUNK_SIZE,
+            chunkmap::DEFAULT_MAIN_SIZE,
+            chunkmap::DEFAULT_EDEN_SIZE,
+        ),
+        Algorithm::Chunked => (chunked::CHUNK_SIZE, chunked::DEFAULT_CACHE_SIZE, 0),
+        Algorithm::Flate | Algorithm::Zstd => (0, 0, 0),
+    };
+    let policy = match algorithm {
+        Algorithm::ChunkMap | Algorithm::Chunked => Some(Policy::default()),
+        Algorithm::Flate | Algorithm::Zstd => None,
+    };
+    Header {
+        algorithm,
+        record_checksum: false,
+        stream_checksum: true,
+        entropy: false,
+        chunk_size,
+        cache_size,
+        eden_size,
+        policy,
+        memory_budget: 0,
+        fingerprint_interval: 0,
+        dict_id: 0,
+    }
+}
+
+/// `level`, `min_match` and `insert_policy` only matter to encoders, decoders
+/// can pass anything.
+fn compressor_for(
+    header: &Header,
+    level: i32,
+    min_match: usize,
+    insert_policy: InsertPolicy,
+    dict: Option<&Dictionary>,
+) -> Result<Box<dyn Compressor>, DsscError> {
+    let supported = match header.algorithm {
+        Algorithm::ChunkMap => {
+            (chunkmap::MIN_CHUNK_SIZE..=chunkmap::MAX_CHUNK_SIZE).contains(&header.chunk_size)
+                && header.cache_size != 0
+                && header.policy.is_some()
+                && (chunkmap::LEVEL_GREEDY..=chunkmap::LEVEL_OPTIMAL).contains(&level)
+        }
+        Algorithm::Chunked => {
+            header.chunk_size == chunked::CHUNK_SIZE
+                && header.cache_size != 0
+                && header.eden_size == 0
+                && header.policy.is_some()
+        }
+        Algorithm::Flate | Algorithm::Zstd => {
+            header.chunk_size == 0
+                && header.cache_size == 0
+                && header.eden_size == 0
+                && header.policy.is_none()
+                && header.memory_budget == 0
+        }
+    };
+    if !supported {
+        // only what the algorithm reads, and for flate and zstd whatever they
+        // cannot take
+        let cache = match header.algorithm {
+            Algorithm::ChunkMap | Algorithm::Chunked => true,
+            Algorithm::Flate | Algorithm::Zstd => false,
+        };
+        let mut settings = Vec::new();
+        if header.algorithm == Algorithm::ChunkMap {
+            settings.push(format!("level {}", level));
+        }
+        if cache || header.chunk_size != 0 {
+            settings.push(format!("chunk size {}", header.chunk_size));
+        }
+        if cache || header.cache_size != 0 {
+            settings.push(format!("cache size {}", header.cache_size));
+        }
+        if cache || header.eden_size != 0 {
+            settings.push(format!("eden size {}", header.eden_size));
+        }
+        if cache || header.policy.is_some() {
+            settings.push(format!("policy {}", header.policy.map(Policy::name).unwrap_or("none")));
+        }
+        if cache || header.memory_budget != 0 {
+            settings.push(format!("memory budget {}", header.memory_budget));
+        }
+        return Err(DsscError::Incompatible(format!(
+            "{} with {}",
+            header.algorithm.name(),
+            settings.join(", ")
+        )));
+    }
+    let mut comp: Box<dyn Compressor> = match header.algorithm {
+        Algorithm::ChunkMap => {
+            let mut builder = ChunkMap::builder()
+                .insert_policy(insert_policy)
+                .chunk_size(header.chunk_size)
+                .main_size(header.cache_size)
+                .eden_size(header.eden_size)
+                .policy(header.policy.unwrap())
+                .level(level)
+                .min_match(min_match);
+            if header.memory_budget != 0 {
+                builder = builder.memory_budget(header.memory_budget);
+            }
+            Box::new(builder.build())
+        }
+        Algorithm::Chunked => {
+            let mut builder = ChunkedCompressor::builder()
+                .insert_policy(insert_policy)
+                .cache_size(header.cache_size)
+                .policy(header.policy.unwrap());
+            if header.memory_budget != 0 {
+                builder = builder.memory_budget(header.memory_budget);
+            }
+            Box::new(builder.build())
+        }
+        Algorithm::Flate => Box::new(FlateStream::default()),
+        Algorithm::Zstd => Box::new(ZstdBlock::builder().level(level).build()),
+    };
+    match dict {
+        Some(dict) if dict.id() == header.dict_id => comp.prime(dict)?,
+        None if header.dict_id == 0 => {}
+        _ => {
+            return Err(DsscError::Incompatible(format!(
+                "stream needs dictionary {:#010x}, got {:#010x}",
+                header.dict_id,
+                dict.map(|d| d.id()).unwrap_or(0)
+            )))
+        }
+    }
+    if header.entropy {
+        comp = Box::new(Huffman::new(comp));
+    }
+    Ok(comp)
+}
+
+/// Every record is written as `uvarint(len) + payload`, followed by the crc32 of
+/// the original line when `record_checksum` is set, and by the u64 cache
+/// fingerprint after every `fingerprint_interval`th record. With
+/// `stream_checksum` the records are terminated by a zero length frame and the
+/// crc32 of all lines.
+fn encode<R: Read, W: Write>(
+    header: &Header,
+    level: i32,
+    min_match: usize,
+    insert_policy: InsertPolicy,
+    dict: Option<&Dictionary>,
+    input: R,
+    mut output: W,
+) -> Result<(), DsscError> {
+    let mut comp = compressor_for(header, level, min_match, insert_policy, dict)?;
+    header.write(&mut output)?;
     let mut len_buf = [0; 10];
     let mut reader = BufReader::new(input);
+    let mut stream_crc = Crc32::new();
+    let mut record = 0;
     loop {
         let mut ibuf = String::new();
         let n = reader.read_line(&mut ibuf)?;
         if n == 0 {
-            return Ok(());
+            break;
         }
         let mut encoded = Vec::new();
-        comp.encode(ibuf.as_bytes(), &mut encoded);
+        comp.encode(ibuf.as_bytes(), &mut encoded)?;
         let len_len = put_uvarint(&mut len_buf, encoded.len() as u64);
-        output.write(&len_buf[0..len_len])?;
-        output.write(&encoded)?;
+        output.write_all(&len_buf[0..len_len])?;
+        output.write_all(&encoded)?;
+        if header.record_checksum {
+            output.write_u32::<LittleEndian>(crc32(ibuf.as_bytes()))?;
+        }
+        record += 1;
+        if header.fingerprint_interval != 0 && record % header.fingerprint_interval == 0 {
+            output.write_u64::<LittleEndian>(comp.fingerprint())?;
+        }
+        stream_crc.update(ibuf.as_bytes());
+    }
+    if header.stream_checksum {
+        output.write_all(&[0])?;
+        output.write_u32::<LittleEndian>(stream_crc.finish())?;
     }
+    Ok(())
 }
 
 fn decode<R: Read, W: Write>(
-    comp: &mut Compressor,
-    mut input: R,
+    dict: Option<&Dictionary>,
+    input: R,
     mut output: W,
-) -> Result<(), Error> {
+) -> Result<(), DsscError> {
+    let mut input = BufReader::new(input);
+    let header = Header::read(&mut input)?;
+    let mut comp = compressor_for(&header, 0, 0, InsertPolicy::Threshold(DEFAULT_THRESHOLD), dict)?;
+    let mut stream_crc = Crc32::new();
+    let mut record = 0;
     loop {
         let mut buf = Vec::new();
-        let len = read_uvarint(&mut input)?;
-        let n = stdin().take(len as u64).read_to_end(&mut buf)?;
-        if n == 0 {
+        let len = match read_uvarint(&mut input) {
+            Ok(len) => len,
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+                if header.stream_checksum {
+                    return Err(DsscError::MissingTrailer);
+                }
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if len == 0 && header.stream_checksum {
+            if input.read_u32::<LittleEndian>()? != stream_crc.finish() {
+                return Err(DsscError::StreamChecksumMismatch);
+            }
             return Ok(());
         }
+        let n = input.by_ref().take(len).read_to_end(&mut buf)?;
+        if (n as u64) < len {
+            return Err(DsscError::TruncatedBlock { offset: n, len: len as usize });
+        }
         let mut decoded = Vec::new();
-        comp.decode(&buf, &mut decoded);
+        comp.decode(&buf, &mut decoded)
+            .map_err(|error| DsscError::BadRecord { record, error: Box::new(error) })?;
+        if header.record_checksum && input.read_u32::<LittleEndian>()? != crc32(&decoded) {
+            return Err(DsscError::ChecksumMismatch { record });
+        }
+        let interval = header.fingerprint_interval as u64;
+        if interval != 0
+            && (record + 1) % interval == 0
+            && input.read_u64::<LittleEndian>()? != comp.fingerprint()
+        {
+            return Err(DsscError::CacheDesync { record });
+        }
+        stream_crc.update(&decoded);
         output.write_all(&decoded)?;
+        record += 1;
+    }
+}
+
+fn train<R: Read, W: Write>(
+    max_records: usize,
+    zstd_size: usize,
+    input: R,
+    mut output: W,
+) -> Result<(), DsscError> {
+    let mut reader = BufReader::new(input);
+    let mut samples = Vec::new();
+    loop {
+        let mut sample = Vec::new();
+        if reader.read_until(b'\n', &mut sample)? == 0 {
+            break;
+        }
+        samples.push(sample);
+    }
+    Dictionary::train(&samples, max_records, zstd_size)?.write(&mut output)
+}
+
+fn open_input(name: &str) -> Box<dyn Read> {
+    match name {
+        "-" => Box::new(stdin()),
+        file => Box::new(File::open(file).expect("Could not open input")),
+    }
+}
+
+fn open_output(name: &str) -> Box<dyn Write> {
+    match name {
+        "-" => Box::new(stdout()),
+        file => Box::new(File::create(file).expect("Could not open ouput")),
     }
 }
 
@@ -61,21 +300,97 @@ fn main() {
                 .help("Sets insert threshold for history cache")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("insert-every")
+                .long("insert-every")
+                .help("Inserts every Nth line into the history cache instead of going by --threshold")
+                .conflicts_with("threshold")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("decompress")
                 .short("d")
                 .long("decompress")
-                .help("Switches linedssc into decompress mode"),
+                .help("Switches linedssc into decompress mode, settings are read from the stream"),
+        )
+        .arg(
+            Arg::with_name("checksum")
+                .short("c")
+                .long("checksum")
+                .help("Stores a checksum with every record to catch corruption early"),
+        )
+        .arg(
+            Arg::with_name("entropy")
+                .short("e")
+                .long("entropy")
+                .help("Entropy codes every record with adaptive models shared across the stream"),
+        )
+        .arg(
+            Arg::with_name("fingerprint")
+                .short("f")
+                .long("fingerprint")
+                .help("Stores the cache fingerprint every N records to catch desyncs early")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dict")
+                .long("dict")
+                .help("Primes the history cache with sample lines or a trained dictionary")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("algorithm")
                 .short("a")
                 .long("algorithm")
-                .possible_values(&["chunkmap", "chunked", "flate"])
+                .possible_values(&["chunkmap", "chunked", "flate", "zstd"])
                 .default_value("chunkmap")
                 .help("Switches linedssc to use a different algorithm")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("chunk-size")
+                .short("s")
+                .long("chunk-size")
+                .help("Sets the length of the anchors chunkmap looks matches up by")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache-size")
+                .long("cache-size")
+                .help("Sets the number of lines kept in the history cache, for chunkmap once they have been hit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eden-size")
+                .long("eden-size")
+                .help("Sets the number of new lines chunkmap keeps until their first hit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("policy")
+                .long("policy")
+                .possible_values(&["lru", "lfu", "arc", "fifo"])
+                .help("Sets how the history cache picks lines to evict")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory-budget")
+                .long("memory-budget")
+                .help("Evicts lines to keep the history cache under N bytes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("level")
+                .long("level")
+                .help("Sets the compression level for zstd, or 1 for optimal parsing in chunkmap")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-match")
+                .long("min-match")
+                .help("Sets the shortest match chunkmap writes as a reference")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("input")
                 .default_value("-")
@@ -88,39 +403,123 @@ fn main() {
                 .required(true)
                 .help("File like destination to output compressed data"),
         )
+        .subcommand(
+            SubCommand::with_name("train")
+                .about("Builds a dictionary for --dict from sample lines")
+                .arg(
+                    Arg::with_name("records")
+                        .short("n")
+                        .long("records")
+                        .help("Sets the maximum number of sample lines kept for the history cache")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("zstd-size")
+                        .long("zstd-size")
+                        .help("Sets the maximum size of the zstd dictionary, 0 to skip it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .default_value("-")
+                        .required(true)
+                        .help("File like source to take the sample lines from"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .default_value("-")
+                        .required(true)
+                        .help("File like destination to output the dictionary"),
+                ),
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("train") {
+        let max_records = matches
+            .value_of("records")
+            .map(|n| n.parse().expect("Incorrect format for records"))
+            .unwrap_or(DEFAULT_DICT_RECORDS);
+        let zstd_size = matches
+            .value_of("zstd-size")
+            .map(|n| n.parse().expect("Incorrect format for zstd size"))
+            .unwrap_or(DEFAULT_ZSTD_DICT_SIZE);
+        let input = open_input(matches.value_of("input").unwrap());
+        let output = open_output(matches.value_of("output").unwrap());
+        if let Err(error) = train(max_records, zstd_size, input, output) {
+            eprintln!("error: {}", error);
+            process::exit(1);
+        }
+        return;
+    }
+
     let threshold = matches
         .value_of("threshold")
         .map(|t| t.parse().expect("Incorrect format for threshold"))
         .unwrap_or(DEFAULT_THRESHOLD);
 
-    let mut comp: Box<Compressor> = match matches.value_of("algorithm") {
-        Some("chunkmap") => Box::new(ChunkMap::new(threshold)),
-        Some("chunked") => Box::new(ChunkedCompressor::new(threshold)),
-        Some("flate") => Box::new(FlateStream::default()),
-        Some(_) | None => panic!("Cannot be none"),
+    let insert_policy = match matches.value_of("insert-every") {
+        Some(n) => InsertPolicy::Every(
+            n.parse().ok().filter(|&n| n != 0).expect("Incorrect format for insert every"),
+        ),
+        None => InsertPolicy::Threshold(threshold),
     };
 
-    let input: Box<Read> = match matches.value_of("input") {
-        Some("-") => Box::new(stdin()),
-        Some(file) => Box::new(File::open(file).expect("Could not open input")),
-        _ => panic!("This is not supposed to happen"),
-    };
+    let algorithm = matches
+        .value_of("algorithm")
+        .and_then(Algorithm::from_name)
+        .expect("Cannot be none");
 
-    let output: Box<Write> = match matches.value_of("output") {
-        Some("-") => Box::new(stdout()),
-        Some(file) => Box::new(File::create(file).expect("Could not open ouput")),
-        _ => panic!("This is not supposed to happen"),
-    };
+    let level = matches
+        .value_of("level")
+        .map(|l| l.parse().expect("Incorrect format for level"))
+        .unwrap_or(0);
 
-    if matches.is_present("decompress") {
-        if let Err(error) = decode(comp.deref_mut(), input, output) {
-            eprintln!("error: {}", error);
-        }
+    let min_match = matches
+        .value_of("min-match")
+        .map(|n| n.parse().expect("Incorrect format for min match"))
+        .unwrap_or(0);
+
+    let input = open_input(matches.value_of("input").unwrap());
+    let output = open_output(matches.value_of("output").unwrap());
+
+    let dict = matches.value_of("dict").map(|file| {
+        let mut buf = Vec::new();
+        File::open(file)
+            .and_then(|mut f| f.read_to_end(&mut buf))
+            .expect("Could not read dictionary");
+        Dictionary::load(&buf).expect("Could not parse dictionary")
+    });
+
+    let result = if matches.is_present("decompress") {
+        decode(dict.as_ref(), input, output)
     } else {
-        if let Err(error) = encode(comp.deref_mut(), input, output) {
-            eprintln!("error: {}", error);
+        let mut header = header_for(algorithm);
+        header.record_checksum = matches.is_present("checksum");
+        header.entropy = matches.is_present("entropy");
+        if let Some(chunk_size) = matches.value_of("chunk-size") {
+            header.chunk_size = chunk_size.parse().expect("Incorrect format for chunk size");
+        }
+        if let Some(cache_size) = matches.value_of("cache-size") {
+            header.cache_size = cache_size.parse().expect("Incorrect format for cache size");
+        }
+        if let Some(budget) = matches.value_of("memory-budget") {
+            header.memory_budget = budget.parse().expect("Incorrect format for memory budget");
         }
+        if let Some(policy) = matches.value_of("policy") {
+            header.policy = Policy::from_name(policy);
+        }
+        if let Some(eden_size) = matches.value_of("eden-size") {
+            header.eden_size = eden_size.parse().expect("Incorrect format for eden size");
+        }
+        header.fingerprint_interval = matches
+            .value_of("fingerprint")
+            .map(|n| n.parse().expect("Incorrect format for fingerprint interval"))
+            .unwrap_or(0);
+        header.dict_id = dict.as_ref().map(|d| d.id()).unwrap_or(0);
+        encode(&header, level, min_match, insert_policy, dict.as_ref(), input, output)
+    };
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        process::exit(1);
     }
 }
@@ -1,78 +1,222 @@
+extern crate byteorder;
 extern crate flate2;
 extern crate zstd;
 extern crate zstd_safe;
 
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use self::zstd::block;
 use self::flate2::write::{DeflateDecoder, DeflateEncoder};
 use self::flate2::Compression;
-use super::varint::{put_uvarint, uvarint};
+use super::checksum::crc32;
+use super::dict::Dictionary;
+use super::state::{read_bytes, read_preamble, write_bytes, write_preamble, KIND_ZSTD};
+use super::varint::{next_uvarint, put_uvarint};
 
-use std::io::{Error, ErrorKind, Read, Write};
+use std::io::{Error, Read, Write};
 use std::ptr;
 
-use super::Compressor;
+use super::{Compressor, DsscError};
 
+/// Largest record ZstdBlock encodes or decodes, so a corrupt length cannot
+/// make the decoder allocate without bound.
+const MAX_RECORD_LEN: u64 = 1 << 30;
+
+/// Compresses every record on its own with zstd, optionally against a shared
+/// dictionary. This is the per-record baseline dssc is measured against.
 pub struct ZstdBlock {
     encoder: block::Compressor,
     decoder: block::Decompressor,
     level: i32,
+    dict: Option<Vec<u8>>,
+    checksum: bool,
+}
+
+pub struct ZstdBlockBuilder {
+    level: i32,
+    dict: Option<Vec<u8>>,
+    checksum: bool,
+}
+
+impl ZstdBlockBuilder {
+    /// zstd compression level, 0 picks the zstd default.
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// A trained or raw content zstd dictionary, both ends need the same one.
+    pub fn dictionary(mut self, dict: Vec<u8>) -> Self {
+        self.dict = Some(dict);
+        self
+    }
+
+    /// Appends the crc32 of every record and checks it when decoding.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn build(self) -> ZstdBlock {
+        let (encoder, decoder) = match self.dict {
+            Some(ref dict) => (
+                block::Compressor::with_dict(dict.clone()),
+                block::Decompressor::with_dict(dict.clone()),
+            ),
+            None => (block::Compressor::new(), block::Decompressor::new()),
+        };
+        ZstdBlock {
+            encoder,
+            decoder,
+            level: self.level,
+            dict: self.dict,
+            checksum: self.checksum,
+        }
+    }
 }
 
 impl ZstdBlock {
-    fn new(level: i32, dict: Option<Vec<u8>>) -> Self {
-        if dict.is_some() {
-            let dict = dict.unwrap();
-            ZstdBlock {
-                encoder: block::Compressor::with_dict(dict.clone()),
-                decoder: block::Decompressor::with_dict(dict),
-                level: level,
-            }
-        } else {
-            ZstdBlock {
-                encoder: block::Compressor::new(),
-                decoder: block::Decompressor::new(),
-                level: level,
-            }
+    pub fn builder() -> ZstdBlockBuilder {
+        ZstdBlockBuilder {
+            level: 0,
+            dict: None,
+            checksum: false,
         }
     }
 }
 
 impl Default for ZstdBlock {
     fn default() -> Self {
-        ZstdBlock::new(0, None)
+        ZstdBlock::builder().build()
     }
 }
 
 impl Compressor for ZstdBlock {
-    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) {
+    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
+        let old_buf_len = out_buf.len();
+        if in_buf.len() as u64 > MAX_RECORD_LEN {
+            return Err(DsscError::BadRecordLength { len: in_buf.len() as u64 });
+        }
         let buffer_len = zstd_safe::compress_bound(in_buf.len());
         let mut varint_buf = [0; 10];
         let varint_len = put_uvarint(&mut varint_buf, in_buf.len() as u64);
         out_buf.extend_from_slice(&varint_buf[0..varint_len]);
 
         let original_len = out_buf.len();
-        out_buf.reserve(buffer_len);
-
-        unsafe { out_buf.set_len(original_len + buffer_len) }
-        let len = self.encoder
-            .compress_to_buffer(in_buf, &mut out_buf[original_len + 4..], self.level)
-            .expect("Compression failed");
-        unsafe { out_buf.set_len(original_len + len) }
+        let end = match original_len.checked_add(buffer_len) {
+            Some(end) => end,
+            None => {
+                out_buf.truncate(old_buf_len);
+                return Err(DsscError::BadRecordLength { len: in_buf.len() as u64 });
+            }
+        };
+        out_buf.resize(end, 0);
+        match self.encoder
+            .compress_to_buffer(in_buf, &mut out_buf[original_len..], self.level)
+        {
+            Ok(len) => out_buf.truncate(original_len + len),
+            Err(err) => {
+                out_buf.truncate(old_buf_len);
+                return Err(DsscError::Backend(err.to_string()));
+            }
+        }
+        if self.checksum {
+            out_buf.write_u32::<LittleEndian>(crc32(in_buf))?;
+        }
+        Ok(out_buf.len() - old_buf_len)
     }
 
-    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) {
+    fn decode(&mut self, mut in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
         let original_len = out_buf.len();
-        let (decomp_len, varint_len) = uvarint(&in_buf);
-        out_buf.reserve(decomp_len as usize);
+        let mut i = 0;
+        let decomp_len = next_uvarint(in_buf, &mut i)?;
+        let mut expected_crc = None;
+        if self.checksum {
+            if in_buf.len() - i < 4 {
+                return Err(DsscError::TruncatedBlock { offset: i, len: 4 });
+            }
+            let (data, mut crc) = in_buf.split_at(in_buf.len() - 4);
+            expected_crc = Some(crc.read_u32::<LittleEndian>()?);
+            in_buf = data;
+        }
 
-        unsafe { out_buf.set_len(original_len + decomp_len as usize) }
-        self.decoder
-            .decompress_to_buffer(&in_buf[varint_len as usize..], &mut out_buf[original_len..])
-            .expect("Decompression failed");
+        // Every frame we write records its content size, so a length that
+        // disagrees with it, or one past the cap, is corrupt.
+        if decomp_len > MAX_RECORD_LEN
+            || zstd_safe::get_frame_content_size(&in_buf[i..]) != decomp_len
+        {
+            return Err(DsscError::BadRecordLength { len: decomp_len });
+        }
+        let end = match original_len.checked_add(decomp_len as usize) {
+            Some(end) => end,
+            None => return Err(DsscError::BadRecordLength { len: decomp_len }),
+        };
+        out_buf.resize(end, 0);
+        let len = match self.decoder
+            .decompress_to_buffer(&in_buf[i..], &mut out_buf[original_len..])
+        {
+            Ok(len) => len,
+            Err(err) => {
+                out_buf.truncate(original_len);
+                return Err(DsscError::Backend(err.to_string()));
+            }
+        };
+        out_buf.truncate(original_len + len);
+        if let Some(crc) = expected_crc {
+            if crc != crc32(&out_buf[original_len..]) {
+                out_buf.truncate(original_len);
+                return Err(DsscError::BadChecksum);
+            }
+        }
+        Ok(len)
     }
-}
 
+    /// Uses the trained zstd dictionary if there is one, otherwise the
+    /// concatenated samples as a raw content dictionary.
+    fn prime(&mut self, dict: &Dictionary) -> Result<(), DsscError> {
+        let content = match dict.zstd {
+            Some(ref zstd) => zstd.clone(),
+            None => dict.records.concat(),
+        };
+        *self = ZstdBlock::builder()
+            .level(self.level)
+            .dictionary(content)
+            .checksum(self.checksum)
+            .build();
+        Ok(())
+    }
 
+    fn save_state(&self, w: &mut dyn Write) -> Result<(), DsscError> {
+        write_preamble(w, KIND_ZSTD)?;
+        w.write_i32::<LittleEndian>(self.level)?;
+        w.write_u8(self.checksum as u8)?;
+        match self.dict {
+            Some(ref dict) => {
+                w.write_u8(1)?;
+                write_bytes(w, dict)?;
+            }
+            None => w.write_u8(0)?,
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, r: &mut dyn Read) -> Result<(), DsscError> {
+        read_preamble(r, KIND_ZSTD)?;
+        let mut builder = ZstdBlock::builder()
+            .level(r.read_i32::<LittleEndian>()?)
+            .checksum(r.read_u8()? != 0);
+        match r.read_u8()? {
+            0 => {}
+            1 => builder = builder.dictionary(read_bytes(r)?),
+            _ => return Err(DsscError::CorruptState("bad dictionary tag")),
+        };
+        *self = builder.build();
+        Ok(())
+    }
+}
+
+/// A single deflate stream shared by all records. Every record is finished
+/// with a sync flush, so each encoded record can be inflated as soon as it
+/// arrives, as long as records are decoded in the order they were encoded.
 pub struct FlateStream {
     encoder: DeflateEncoder<WriteProxy<Vec<u8>>>,
     decoder: DeflateDecoder<WriteProxy<Vec<u8>>>,
@@ -88,17 +232,21 @@ impl Default for FlateStream {
 }
 
 impl Compressor for FlateStream {
-    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) {
+    fn encode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
+        let old_buf_len = out_buf.len();
         let guard = self.encoder.get_mut().set(out_buf);
-        self.encoder.write(&in_buf);
-        self.encoder.flush();
+        self.encoder.write_all(in_buf)?;
+        self.encoder.flush()?;
         drop(guard);
+        Ok(out_buf.len() - old_buf_len)
     }
-    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) {
-        let guard = self.encoder.get_mut().set(out_buf);
-        self.encoder.write(&in_buf);
-        self.encoder.flush();
+    fn decode(&mut self, in_buf: &[u8], out_buf: &mut Vec<u8>) -> Result<usize, DsscError> {
+        let old_buf_len = out_buf.len();
+        let guard = self.decoder.get_mut().set(out_buf);
+        self.decoder.write_all(in_buf)?;
+        self.decoder.flush()?;
         drop(guard);
+        Ok(out_buf.len() - old_buf_len)
     }
 }
 /*
@@ -149,7 +297,7 @@ unsafe impl<W: Write + Sized> Send for WriteProxy<W> {}
 impl<W: Write + Sized> Write for WriteProxy<W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         if self.inner.is_null() {
-            Err(Error::new(ErrorKind::Other, "No writer in proxy"))
+            Err(Error::other("No writer in proxy"))
         } else {
             unsafe { (&mut *self.inner).write(buf) }
         }
@@ -157,13 +305,19 @@ impl<W: Write + Sized> Write for WriteProxy<W> {
 
     fn flush(&mut self) -> Result<(), Error> {
         if self.inner.is_null() {
-            Err(Error::new(ErrorKind::Other, "No writer in proxy"))
+            Err(Error::other("No writer in proxy"))
         } else {
             unsafe { (&mut *self.inner).flush() }
         }
     }
 }
 
+impl<W: Write + Sized> Default for WriteProxy<W> {
+    fn default() -> Self {
+        WriteProxy::new()
+    }
+}
+
 impl<W: Write + Sized> WriteProxy<W> {
     pub fn new() -> Self {
         WriteProxy { inner: ptr::null_mut() }
@@ -178,3 +332,47 @@ impl<W: Write + Sized> WriteProxy<W> {
         self.inner = ptr::null_mut();
     }
 }
+
+#[test]
+pub fn zstd_test() {
+    let line = b"Jan 12 10:00:01 host sshd[100]: Accepted publickey for root\n";
+    let mut comp = ZstdBlock::builder()
+        .level(3)
+        .dictionary(b"sshd Accepted publickey for".to_vec())
+        .checksum(true)
+        .build();
+    let mut buf = Vec::new();
+    comp.encode(line, &mut buf).unwrap();
+    let mut out = Vec::new();
+    comp.decode(&buf, &mut out).unwrap();
+    assert_eq!(&out[..], &line[..]);
+
+    let last = buf.len() - 1;
+    buf[last] ^= 1;
+    assert!(comp.decode(&buf, &mut Vec::new()).is_err());
+
+    let huge = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 1, 2, 3];
+    let mut out = b"kept".to_vec();
+    match ZstdBlock::default().decode(&huge, &mut out) {
+        Err(DsscError::BadRecordLength { .. }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(&out[..], b"kept");
+}
+
+#[test]
+pub fn flate_test() {
+    let corpus = include_bytes!("../corpus/logs.txt");
+    let mut encoder = FlateStream::default();
+    let mut decoder = FlateStream::default();
+    let mut compressed = 0;
+    for line in corpus.split_inclusive(|&b| b == b'\n') {
+        let mut buf = Vec::new();
+        encoder.encode(line, &mut buf).unwrap();
+        compressed += buf.len();
+        let mut out = Vec::new();
+        decoder.decode(&buf, &mut out).unwrap();
+        assert_eq!(&out[..], line);
+    }
+    assert!(compressed < corpus.len());
+}
@@ -0,0 +1,78 @@
+extern crate byteorder;
+
+use self::byteorder::{ReadBytesExt, WriteBytesExt};
+
+use super::varint::{read_uvarint, write_uvarint};
+use super::DsscError;
+
+use std::io::{Read, Write};
+
+// Snapshots of compressor state, so a restarted process can resume with a
+// warm cache. Every snapshot starts with the magic, the state version and the
+// kind of compressor that wrote it; the rest is up to the compressor.
+
+pub const STATE_MAGIC: [u8; 4] = *b"DSST";
+pub const STATE_VERSION: u8 = 1;
+
+pub const KIND_CHUNKMAP: u8 = 1;
+pub const KIND_CHUNKED: u8 = 2;
+pub const KIND_ZSTD: u8 = 3;
+pub const KIND_HUFFMAN: u8 = 4;
+
+pub fn write_preamble(w: &mut dyn Write, kind: u8) -> Result<(), DsscError> {
+    w.write_all(&STATE_MAGIC)?;
+    w.write_u8(STATE_VERSION)?;
+    w.write_u8(kind)?;
+    Ok(())
+}
+
+pub fn read_preamble(r: &mut dyn Read, kind: u8) -> Result<(), DsscError> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if magic != STATE_MAGIC {
+        return Err(DsscError::BadMagic);
+    }
+    let version = r.read_u8()?;
+    if version != STATE_VERSION {
+        return Err(DsscError::UnsupportedVersion { version });
+    }
+    let found = r.read_u8()?;
+    if found != kind {
+        return Err(DsscError::Incompatible(format!(
+            "state of compressor kind {} cannot be loaded into kind {}",
+            found, kind
+        )));
+    }
+    Ok(())
+}
+
+pub fn write_bytes(w: &mut dyn Write, buf: &[u8]) -> Result<(), DsscError> {
+    write_uvarint(w, buf.len() as u64)?;
+    w.write_all(buf)?;
+    Ok(())
+}
+
+pub fn read_bytes(r: &mut dyn Read) -> Result<Vec<u8>, DsscError> {
+    let len = read_uvarint(r)?;
+    let mut buf = Vec::new();
+    let n = r.take(len).read_to_end(&mut buf)?;
+    if (n as u64) < len {
+        return Err(DsscError::TruncatedBlock { offset: n, len: len as usize });
+    }
+    Ok(buf)
+}
+
+/// Slots are cache indexes, anything past `u32::MAX` cannot have been written
+/// by a cache.
+pub fn write_slot(w: &mut dyn Write, slot: usize) -> Result<(), DsscError> {
+    write_uvarint(w, slot as u64)?;
+    Ok(())
+}
+
+pub fn read_slot(r: &mut dyn Read) -> Result<usize, DsscError> {
+    let slot = read_uvarint(r)?;
+    if slot > u32::MAX as u64 {
+        return Err(DsscError::CorruptState("slot out of range"));
+    }
+    Ok(slot as usize)
+}
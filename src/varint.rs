@@ -1,4 +1,5 @@
-use std::io::{Error, ErrorKind, Read};
+use std::io::{Error, ErrorKind, Read, Write};
+use super::DsscError;
 // these are roughly ported from https://golang.org/src/encoding/binary/varint.go
 
 pub fn put_uvarint(buf: &mut [u8], mut x: u64) -> usize {
@@ -9,16 +10,23 @@ pub fn put_uvarint(buf: &mut [u8], mut x: u64) -> usize {
         i += 1;
     }
     buf[i] = x as u8;
-    return i + 1;
+    i + 1
+}
+
+/// Bytes `put_uvarint` takes for `x`.
+pub fn uvarint_len(x: u64) -> usize {
+    (64 - (x | 1).leading_zeros() as usize).div_ceil(7)
 }
 
 pub fn uvarint(buf: &[u8]) -> (u64, isize) {
     let mut x = 0u64;
     let mut s = 0isize;
-    for i in 0..buf.len() {
-        let b = buf[i];
+    for (i, &b) in buf.iter().enumerate() {
+        if i > 9 {
+            return (0u64, -1); // overflow, before shifting past 64 bits
+        }
         if b < 0x80 {
-            if i > 9 || i == 9 && b > 1 {
+            if i == 9 && b > 1 {
                 return (0u64, -1); // overflow
             }
             return (x | (b as u64) << s, (i + 1) as isize);
@@ -29,16 +37,36 @@ pub fn uvarint(buf: &[u8]) -> (u64, isize) {
     (0u64, 0isize)
 }
 
-pub fn read_uvarint(r: &mut Read) -> Result<u64, Error> {
+/// Decodes the varint at `buf[*pos..]` and advances `pos` past it. Only the
+/// shortest encoding of a value is accepted, as produced by `put_uvarint`.
+pub fn next_uvarint(buf: &[u8], pos: &mut usize) -> Result<u64, DsscError> {
+    let (x, len) = uvarint(&buf[(*pos).min(buf.len())..]);
+    if len == 0 {
+        return Err(DsscError::TruncatedVarint { offset: *pos });
+    }
+    if len < 0 {
+        return Err(DsscError::VarintOverflow { offset: *pos });
+    }
+    if len > 1 && buf[*pos + len as usize - 1] == 0 {
+        return Err(DsscError::OverlongVarint { offset: *pos });
+    }
+    *pos += len as usize;
+    Ok(x)
+}
+
+pub fn read_uvarint(r: &mut dyn Read) -> Result<u64, Error> {
     let mut x = 0u64;
     let mut s = 0isize;
     let mut i = 0;
     let mut b = [0; 1];
     loop {
-        r.read(&mut b)?;
+        if i > 9 {
+            return Err(Error::new(ErrorKind::InvalidData, "Overflow"));
+        }
+        r.read_exact(&mut b)?;
         if b[0] < 0x80 {
             if i > 9 || i == 9 && b[0] > 1 {
-                return Err(Error::new(ErrorKind::Other, "Overflow")); // overflow
+                return Err(Error::new(ErrorKind::InvalidData, "Overflow")); // overflow
             }
             return Ok(x | (b[0] as u64) << s);
         }
@@ -48,6 +76,13 @@ pub fn read_uvarint(r: &mut Read) -> Result<u64, Error> {
     }
 }
 
+pub fn write_uvarint(w: &mut dyn Write, x: u64) -> Result<usize, Error> {
+    let mut buf = [0; 10];
+    let len = put_uvarint(&mut buf, x);
+    w.write_all(&buf[0..len])?;
+    Ok(len)
+}
+
 #[test]
 pub fn varint_test() {
     let mut buf = [0; 9];
@@ -57,3 +92,21 @@ pub fn varint_test() {
     println!("{} {}", after, size);
     assert_eq!(val, after);
 }
+
+#[test]
+pub fn varint_overflow_test() {
+    // the tenth byte may only add the top bit
+    let mut buf = [0xff; 10];
+    buf[9] = 1;
+    assert_eq!(uvarint(&buf), (u64::MAX, 10));
+    buf[9] = 2;
+    assert_eq!(uvarint(&buf), (0, -1));
+
+    // continuation bytes past the tenth are rejected, not shifted out of range
+    let buf = [0x80; 12];
+    assert_eq!(uvarint(&buf), (0, -1));
+    match next_uvarint(&buf, &mut 0) {
+        Err(DsscError::VarintOverflow { offset: 0 }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}